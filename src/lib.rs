@@ -1,7 +1,11 @@
 extern crate libc;
 #[macro_use] extern crate bitflags;
+#[cfg(feature = "serde")] extern crate serde;
 
 pub mod tcadb;
 pub mod tcbdb;
+pub mod tcfdb;
 pub mod tchdb;
+#[cfg(feature = "serde")] pub mod tcserde;
+pub mod tctdb;
 pub mod tcutil;
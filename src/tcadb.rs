@@ -15,6 +15,12 @@
 //
 
 use libc::{c_char, c_int, c_void};
+use std::cmp;
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+
+use tcutil;
+use tcutil::{ErrorCode, Result, TCEMISC, TCLIST, TcBytes, TcError, list_to_vec, tcerrmsg, tclistdel};
 
 /// TCADB - The Abstract tree database instance.
 #[derive(Copy, Clone)]
@@ -30,6 +36,36 @@ bitflags! {
     }
 }
 
+bitflags! {
+    flags SpecModes : c_int {
+        /// open as a reader (`r`)
+        const ADBMREADER = 1 << 0,
+        /// open as a writer (`w`)
+        const ADBMWRITER = 1 << 1,
+        /// writer creating (`c`)
+        const ADBMCREAT  = 1 << 2,
+        /// writer truncating (`t`)
+        const ADBMTRUNC  = 1 << 3,
+        /// open without locking (`e`)
+        const ADBMNOLCK  = 1 << 4,
+        /// lock without blocking (`f`)
+        const ADBMLCKNB  = 1 << 5
+    }
+}
+
+bitflags! {
+    flags SpecOptions : c_int {
+        /// use 64-bit bucket array (`l`)
+        const ADBTLARGE   = 1 << 0,
+        /// compress each record with Deflate (`d`)
+        const ADBTDEFLATE = 1 << 1,
+        /// compress each record with BZIP2 (`b`)
+        const ADBTBZIP    = 1 << 2,
+        /// compress each record with TCBS (`t`)
+        const ADBTTCBS    = 1 << 3
+    }
+}
+
 /*
  * In the following, I use *const u8 for void* since void* has no size, so,
  * afaik, using c_void would just force further casts elsewhere.
@@ -39,17 +75,370 @@ bitflags! {
 extern {
     pub fn tcadbnew() -> TCADB;
     pub fn tcadbdel(db: TCADB);
-    pub fn tcadbopen(db: TCADB, name: *const c_char);
-    pub fn tcadbclose(db: TCADB);
+    pub fn tcadbopen(db: TCADB, name: *const c_char) -> bool;
+    pub fn tcadbclose(db: TCADB) -> bool;
     pub fn tcadbput(db: TCADB, kbuf: *const u8, ksiz: c_int, vbuf: *const u8, vsiz: c_int) -> bool;
     pub fn tcadbputkeep(db: TCADB, kbuf: *const u8, ksiz: c_int, vbuf: *const u8, vsiz: c_int) -> bool;
     pub fn tcadbputkeep2(db: TCADB, kstr: *const c_char, vstr: *const c_char) -> bool;
-    pub fn tcadbout(db: TCADB, kbuf: *const u8, ksiz: c_int);
-    pub fn tcadbget(db: TCADB, kbuf: *const u8, ksiz: c_int, sp: *mut *const c_int) -> *const u8;
+    pub fn tcadbout(db: TCADB, kbuf: *const u8, ksiz: c_int) -> bool;
+    pub fn tcadbget(db: TCADB, kbuf: *const u8, ksiz: c_int, sp: *mut c_int) -> *mut u8;
+    pub fn tcadbvsiz(db: TCADB, kbuf: *const u8, ksiz: c_int) -> c_int;
+    pub fn tcadbfwmkeys(db: TCADB, pbuf: *const u8, psiz: c_int, max: c_int) -> TCLIST;
     pub fn tcadbsync(db: TCADB) -> bool;
     pub fn tcadbvanish(db: TCADB) -> bool;
 }
 
+/// AdbBackend - The concrete database behind an abstract database.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AdbBackend {
+    /// on-memory hash database (`*`)
+    MemoryHash,
+    /// on-memory tree database (`+`)
+    MemoryTree,
+    /// hash database file (`.tch` or `.hdb`)
+    HashFile,
+    /// B+ tree database file (`.tcb` or `.bdb`)
+    BTreeFile,
+    /// fixed-length database file (`.tcf` or `.fdb`)
+    FixedFile,
+    /// table database file (`.tct` or `.tdb`)
+    TableFile,
+}
+
+impl AdbBackend {
+    fn suffixes(&self) -> &'static [&'static str] {
+        match *self {
+            AdbBackend::MemoryHash => &["*"],
+            AdbBackend::MemoryTree => &["+"],
+            AdbBackend::HashFile => &["tch", "hdb"],
+            AdbBackend::BTreeFile => &["tcb", "bdb"],
+            AdbBackend::FixedFile => &["tcf", "fdb"],
+            AdbBackend::TableFile => &["tct", "tdb"],
+        }
+    }
+
+    /// Whether `tcadbopen` picks this backend for `path`, which like
+    /// `tcstribwm` compares the extension case-insensitively.
+    fn matches(&self, path: &Path) -> bool {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(ext) => self.suffixes().iter().any(|s| s.eq_ignore_ascii_case(ext)),
+            None => false,
+        }
+    }
+
+    fn accepts(&self, param: &str) -> bool {
+        let params: &[&str] = match *self {
+            AdbBackend::MemoryHash => &["bnum", "capnum", "capsiz"],
+            AdbBackend::MemoryTree => &["capnum", "capsiz"],
+            AdbBackend::HashFile => &["mode", "bnum", "apow", "fpow", "opts",
+                                      "rcnum", "xmsiz", "dfunit"],
+            AdbBackend::BTreeFile => &["mode", "lmemb", "nmemb", "bnum", "apow", "fpow",
+                                       "opts", "lcnum", "ncnum", "xmsiz", "dfunit"],
+            AdbBackend::FixedFile => &["mode", "width", "limsiz"],
+            AdbBackend::TableFile => &["mode", "bnum", "apow", "fpow", "opts", "rcnum",
+                                       "lcnum", "ncnum", "xmsiz", "dfunit"],
+        };
+        params.contains(&param)
+    }
+}
+
+/// AdbSpec - Builds the name string passed to `tcadbopen`, such as
+/// `casket.tch#mode=wc#bnum=100000`.
+#[derive(Clone, Debug)]
+pub struct AdbSpec {
+    backend: AdbBackend,
+    path: Option<PathBuf>,
+    params: Vec<(&'static str, String)>,
+}
+
+impl AdbSpec {
+    fn new(backend: AdbBackend, path: Option<PathBuf>) -> AdbSpec {
+        AdbSpec { backend, path, params: Vec::new() }
+    }
+
+    /// An on-memory hash database.
+    pub fn memory_hash() -> AdbSpec {
+        AdbSpec::new(AdbBackend::MemoryHash, None)
+    }
+
+    /// An on-memory tree database.
+    pub fn memory_tree() -> AdbSpec {
+        AdbSpec::new(AdbBackend::MemoryTree, None)
+    }
+
+    /// A hash database file; `path` must end in `.tch` or `.hdb`, in any case.
+    pub fn hash_file<P: AsRef<Path>>(path: P) -> AdbSpec {
+        AdbSpec::new(AdbBackend::HashFile, Some(path.as_ref().to_path_buf()))
+    }
+
+    /// A B+ tree database file; `path` must end in `.tcb` or `.bdb`, in any case.
+    pub fn btree_file<P: AsRef<Path>>(path: P) -> AdbSpec {
+        AdbSpec::new(AdbBackend::BTreeFile, Some(path.as_ref().to_path_buf()))
+    }
+
+    /// A fixed-length database file; `path` must end in `.tcf` or `.fdb`, in any case.
+    pub fn fixed_file<P: AsRef<Path>>(path: P) -> AdbSpec {
+        AdbSpec::new(AdbBackend::FixedFile, Some(path.as_ref().to_path_buf()))
+    }
+
+    /// A table database file; `path` must end in `.tct` or `.tdb`, in any case.
+    pub fn table_file<P: AsRef<Path>>(path: P) -> AdbSpec {
+        AdbSpec::new(AdbBackend::TableFile, Some(path.as_ref().to_path_buf()))
+    }
+
+    /// The backend this spec opens.
+    pub fn backend(&self) -> AdbBackend {
+        self.backend
+    }
+
+    fn param<T: ToString>(mut self, name: &'static str, value: T) -> AdbSpec {
+        self.params.retain(|&(n, _)| n != name);
+        self.params.push((name, value.to_string()));
+        self
+    }
+
+    /// Open modes of a database file.
+    pub fn mode(self, modes: SpecModes) -> AdbSpec {
+        let letters = [(ADBMWRITER, 'w'), (ADBMREADER, 'r'), (ADBMCREAT, 'c'),
+                       (ADBMTRUNC, 't'), (ADBMNOLCK, 'e'), (ADBMLCKNB, 'f')];
+        let mode: String = letters.iter()
+            .filter(|&&(flag, _)| modes.contains(flag))
+            .map(|&(_, c)| c)
+            .collect();
+        self.param("mode", mode)
+    }
+
+    /// Number of elements of the bucket array.
+    pub fn bnum(self, bnum: i64) -> AdbSpec {
+        self.param("bnum", bnum)
+    }
+
+    /// Size of record alignment by power of 2.
+    pub fn apow(self, apow: i8) -> AdbSpec {
+        self.param("apow", apow)
+    }
+
+    /// Maximum number of elements of the free block pool by power of 2.
+    pub fn fpow(self, fpow: i8) -> AdbSpec {
+        self.param("fpow", fpow)
+    }
+
+    /// Tuning options of a database file.
+    pub fn opts(self, opts: SpecOptions) -> AdbSpec {
+        let letters = [(ADBTLARGE, 'l'), (ADBTDEFLATE, 'd'), (ADBTBZIP, 'b'), (ADBTTCBS, 't')];
+        let opts: String = letters.iter()
+            .filter(|&&(flag, _)| opts.contains(flag))
+            .map(|&(_, c)| c)
+            .collect();
+        self.param("opts", opts)
+    }
+
+    /// Maximum number of records to be cached.
+    pub fn rcnum(self, rcnum: i32) -> AdbSpec {
+        self.param("rcnum", rcnum)
+    }
+
+    /// Number of members in each leaf page.
+    pub fn lmemb(self, lmemb: i32) -> AdbSpec {
+        self.param("lmemb", lmemb)
+    }
+
+    /// Number of members in each non-leaf page.
+    pub fn nmemb(self, nmemb: i32) -> AdbSpec {
+        self.param("nmemb", nmemb)
+    }
+
+    /// Maximum number of leaf nodes to be cached.
+    pub fn lcnum(self, lcnum: i32) -> AdbSpec {
+        self.param("lcnum", lcnum)
+    }
+
+    /// Maximum number of non-leaf nodes to be cached.
+    pub fn ncnum(self, ncnum: i32) -> AdbSpec {
+        self.param("ncnum", ncnum)
+    }
+
+    /// Size of the extra mapped memory.
+    pub fn xmsiz(self, xmsiz: i64) -> AdbSpec {
+        self.param("xmsiz", xmsiz)
+    }
+
+    /// Unit step number of auto defragmentation.
+    pub fn dfunit(self, dfunit: i32) -> AdbSpec {
+        self.param("dfunit", dfunit)
+    }
+
+    /// Capacity number of records of an on-memory database.
+    pub fn capnum(self, capnum: i64) -> AdbSpec {
+        self.param("capnum", capnum)
+    }
+
+    /// Capacity size of memory used by an on-memory database.
+    pub fn capsiz(self, capsiz: i64) -> AdbSpec {
+        self.param("capsiz", capsiz)
+    }
+
+    /// Width of the value of each record of a fixed-length database.
+    pub fn width(self, width: i32) -> AdbSpec {
+        self.param("width", width)
+    }
+
+    /// Limit size of a fixed-length database file.
+    pub fn limsiz(self, limsiz: i64) -> AdbSpec {
+        self.param("limsiz", limsiz)
+    }
+
+    /// Render the name string, checking that every parameter applies to the backend.
+    pub fn name(&self) -> Result<String> {
+        let invalid = || TcError::new(ErrorCode::Invalid, "tcadbopen", self.path.as_deref());
+        let mut name = match self.path {
+            None => self.backend.suffixes()[0].to_string(),
+            Some(ref path) => {
+                if !self.backend.matches(path) {
+                    return Err(invalid());
+                }
+                match path.to_str() {
+                    Some(p) if !p.contains('#') => p.to_string(),
+                    _ => return Err(invalid()),
+                }
+            }
+        };
+        for &(param, ref value) in &self.params {
+            if !self.backend.accepts(param) {
+                return Err(invalid());
+            }
+            name.push('#');
+            name.push_str(param);
+            name.push('=');
+            name.push_str(value);
+        }
+        Ok(name)
+    }
+}
+
+/// AbstractDb - An owned abstract database.
+///
+/// The underlying `TCADB` is closed and deleted when the `AbstractDb` is dropped.
+/// The abstract API reports no error codes, so failures are reported as
+/// `ErrorCode::Open` when opening and `ErrorCode::Misc` otherwise.
+pub struct AbstractDb {
+    db: TCADB,
+    name: String,
+}
+
+impl AbstractDb {
+    /// Open the database described by `spec`.
+    pub fn open(spec: &AdbSpec) -> Result<AbstractDb> {
+        AbstractDb::open_name(&spec.name()?)
+    }
+
+    /// Open the database described by a raw `tcadbopen` name string.
+    pub fn open_name(name: &str) -> Result<AbstractDb> {
+        let db = AbstractDb { db: unsafe { tcadbnew() }, name: name.to_string() };
+        let cname = match CString::new(name) {
+            Ok(cname) => cname,
+            Err(_) => return Err(db.error(ErrorCode::Invalid, "tcadbopen")),
+        };
+        if unsafe { tcadbopen(db.db, cname.as_ptr()) } {
+            Ok(db)
+        } else {
+            Err(db.error(ErrorCode::Open, "tcadbopen"))
+        }
+    }
+
+    /// Store a record, overwriting any existing value.
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let ok = unsafe {
+            tcadbput(self.db, key.as_ptr(), key.len() as c_int, value.as_ptr(), value.len() as c_int)
+        };
+        self.check(ok, "tcadbput")
+    }
+
+    /// Store a record unless the key already exists.
+    ///
+    /// Returns `false` if the key already exists. The abstract API reports
+    /// no error code, so a refusal with the key still absent is an error.
+    pub fn put_keep(&mut self, key: &[u8], value: &[u8]) -> Result<bool> {
+        let stored = unsafe {
+            tcadbputkeep(self.db, key.as_ptr(), key.len() as c_int, value.as_ptr(), value.len() as c_int)
+        };
+        if stored || self.contains(key) { Ok(stored) } else { Err(self.error(ErrorCode::Misc, "tcadbputkeep")) }
+    }
+
+    /// Retrieve the value of a record.
+    pub fn get(&self, key: &[u8]) -> Result<Option<TcBytes>> {
+        let mut size: c_int = 0;
+        let vbuf = unsafe { tcadbget(self.db, key.as_ptr(), key.len() as c_int, &mut size) };
+        if vbuf.is_null() {
+            return Ok(None);
+        }
+        Ok(Some(unsafe { TcBytes::from_raw(vbuf, size as usize) }))
+    }
+
+    /// Remove a record.
+    ///
+    /// Returns `false` if there was no such record; a record left in place
+    /// is an error.
+    pub fn remove(&mut self, key: &[u8]) -> Result<bool> {
+        let removed = unsafe { tcadbout(self.db, key.as_ptr(), key.len() as c_int) };
+        if removed || !self.contains(key) { Ok(removed) } else { Err(self.error(ErrorCode::Misc, "tcadbout")) }
+    }
+
+    /// The keys beginning with `prefix`, at most `max` of them if given.
+    pub fn keys_with_prefix(&self, prefix: &[u8], max: Option<usize>) -> Result<Vec<Vec<u8>>> {
+        let max = max.map_or(-1, |m| cmp::min(m, c_int::MAX as usize) as c_int);
+        unsafe {
+            let keys = tcadbfwmkeys(self.db, prefix.as_ptr(), prefix.len() as c_int, max);
+            let result = list_to_vec(keys);
+            tclistdel(keys);
+            Ok(result)
+        }
+    }
+
+    /// Synchronize updated contents with the file and the device.
+    pub fn sync(&mut self) -> Result<()> {
+        self.check(unsafe { tcadbsync(self.db) }, "tcadbsync")
+    }
+
+    /// Remove all records.
+    pub fn vanish(&mut self) -> Result<()> {
+        self.check(unsafe { tcadbvanish(self.db) }, "tcadbvanish")
+    }
+
+    /// Close the database, reporting whether it was closed cleanly.
+    pub fn close(self) -> Result<()> {
+        self.check(unsafe { tcadbclose(self.db) }, "tcadbclose")
+    }
+
+    /// The raw handle, for calling functions not wrapped here.
+    pub fn as_raw(&self) -> TCADB {
+        self.db
+    }
+
+    fn contains(&self, key: &[u8]) -> bool {
+        unsafe { tcadbvsiz(self.db, key.as_ptr(), key.len() as c_int) >= 0 }
+    }
+
+    fn error(&self, code: ErrorCode, op: &'static str) -> TcError {
+        TcError::new(code, op, Some(Path::new(&self.name)))
+    }
+
+    fn check(&self, ok: bool, op: &'static str) -> Result<()> {
+        tcutil::check(adb_ecode, tcerrmsg, self.db, Path::new(&self.name), ok, op)
+    }
+}
+
+// The abstract API keeps no error code, so every failure reads as `TCEMISC`.
+unsafe extern "C" fn adb_ecode(_: TCADB) -> c_int {
+    TCEMISC
+}
+
+impl Drop for AbstractDb {
+    fn drop(&mut self) {
+        unsafe { tcadbdel(self.db) };
+    }
+}
+
 #[cfg(test)]
 mod test {
     use libc::{c_int, c_void};
@@ -87,21 +476,75 @@ mod test {
 
             let rustpath = "+"; // "+" is shorthand for in memory b+tree in tc
             let cpath = CString::new(rustpath).unwrap();
-            tcadbopen(db, cpath.as_ptr());
+            assert!(tcadbopen(db, cpath.as_ptr()));
             let k = b"hello";
             let v = b"world";
             assert!(tcadbput(db, k.clone().as_ptr(), k.len() as c_int, v.clone().as_ptr(), v.len() as c_int));
-            let mut v2_sz: *const i32 = 0 as *const i32;
-            let v2_sz_ptr: *mut *const i32 = &mut v2_sz;
-            let v2 = tcadbget(db, k.as_ptr(), k.len() as c_int, v2_sz_ptr);
+            let mut v2_sz: c_int = 0;
+            let v2 = tcadbget(db, k.as_ptr(), k.len() as c_int, &mut v2_sz);
             assert!(!v2.is_null());
             assert_eq!(v.len(), v2_sz as usize);
             let v2_slice = slice::from_raw_parts(v2, v2_sz as usize);
             assert_eq!(v, v2_slice);
 
             tcfree(v2 as *const c_void);
-            tcadbclose(db);
+            assert!(tcadbout(db, k.as_ptr(), k.len() as c_int));
+            assert!(!tcadbout(db, k.as_ptr(), k.len() as c_int));
+            assert!(tcadbclose(db));
             tcadbdel(db);
         }
     }
+
+    #[test]
+    fn test_adbspec_names() {
+        assert_eq!(AdbSpec::memory_hash().bnum(1000).name().unwrap(), "*#bnum=1000");
+        assert_eq!(AdbSpec::memory_tree().capnum(10).capsiz(4096).name().unwrap(),
+                   "+#capnum=10#capsiz=4096");
+        assert_eq!(AdbSpec::hash_file("casket.tch").mode(ADBMWRITER | ADBMCREAT)
+                       .bnum(100000).opts(ADBTLARGE | ADBTDEFLATE).name().unwrap(),
+                   "casket.tch#mode=wc#bnum=100000#opts=ld");
+        assert_eq!(AdbSpec::fixed_file("casket.tcf").width(255).width(128).name().unwrap(),
+                   "casket.tcf#width=128");
+        assert_eq!(AdbSpec::hash_file("casket.HDB").name().unwrap(), "casket.HDB");
+        assert_eq!(AdbSpec::table_file("casket.Tct").mode(ADBMREADER).name().unwrap(),
+                   "casket.Tct#mode=r");
+        assert_eq!(AdbSpec::hash_file("casket.tcb").name().err().map(|e| e.code()), Some(ErrorCode::Invalid));
+        assert_eq!(AdbSpec::memory_tree().bnum(10).name().err().map(|e| e.code()), Some(ErrorCode::Invalid));
+        assert_eq!(AdbSpec::fixed_file("cask#et.tcf").name().err().map(|e| e.code()), Some(ErrorCode::Invalid));
+    }
+
+    #[test]
+    fn test_abstractdb_basics() {
+        let rustpath = ".tcadb_test_abstractdb_basics.tcb";
+        {
+            let spec = AdbSpec::btree_file(rustpath).mode(ADBMWRITER | ADBMCREAT | ADBMTRUNC).lmemb(64);
+            let mut db = AbstractDb::open(&spec).unwrap();
+            db.put(b"hello", b"world").unwrap();
+            assert_eq!(db.get(b"hello").unwrap().unwrap(), &b"world"[..]);
+            assert!(db.get(b"missing").unwrap().is_none());
+            assert!(!db.put_keep(b"hello", b"there").unwrap());
+            assert!(db.put_keep(b"hi", b"there").unwrap());
+            assert!(db.remove(b"hello").unwrap());
+            assert!(!db.remove(b"hello").unwrap());
+            db.sync().unwrap();
+            db.vanish().unwrap();
+            assert!(db.get(b"hi").unwrap().is_none());
+            db.close().unwrap();
+        }
+        assert!(::std::fs::remove_file(rustpath).is_ok());
+        assert_eq!(AbstractDb::open(&AdbSpec::hash_file(".tcadb_test_missing.tch")
+                                        .mode(ADBMREADER)).err().map(|e| e.code()),
+                   Some(ErrorCode::Open));
+    }
+
+    #[test]
+    fn test_abstractdb_keys_with_prefix() {
+        let mut db = AbstractDb::open(&AdbSpec::memory_tree()).unwrap();
+        for k in &[&b"user:2"[..], b"user:1", b"group:1"] {
+            db.put(k, b"").unwrap();
+        }
+        assert_eq!(db.keys_with_prefix(b"user:", None).unwrap(),
+                   vec![b"user:1".to_vec(), b"user:2".to_vec()]);
+        assert_eq!(db.keys_with_prefix(b"user:", Some(1)).unwrap(), vec![b"user:1".to_vec()]);
+    }
 }
@@ -15,8 +15,16 @@
 //
 
 use libc::{c_char, c_int, c_long, c_void};
+use std::cmp::{self, Ordering};
+use std::ops::{Bound, Deref, DerefMut, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::rc::Rc;
 
-use tcutil::TCCMP;
+use tcutil;
+use tcutil::{ErrorCode, Result, TCCMP, TCLIST, TCXSTR, TcBytes, TcError, cmp_callback, list_from_slices,
+             list_to_vec, path_to_cstring, tclistdel, tcxstrdel,
+             tcxstrnew, xstr_to_vec};
 
 /// TCBDB - The B+ tree database instance.
 #[derive(Copy, Clone)]
@@ -96,10 +104,20 @@ extern {
     pub fn tcbdbdel(bdb: TCBDB);
     pub fn tcbdbecode(bdb: TCBDB)-> c_int;
     pub fn tcbdbsetmutex(bdb: TCBDB) -> bool;
-    pub fn tcbdbsetcmpfunc(bdb: TCBDB, cmp: TCCMP, cmpop: *const u8);
-    // TODO: check types since these are defined pretty specifically...
-    pub fn tcbdbtune(bdb: TCBDB, lmemb: c_int, nmemb: c_int,
-                   bnum: c_long, bnum: c_char, fpow: c_char, opts: c_char) -> bool;
+    pub fn tcbdbsetcmpfunc(bdb: TCBDB, cmp: TCCMP, cmpop: *const u8) -> bool;
+    pub fn tcbdbcmpfunc(bdb: TCBDB) -> TCCMP;
+    pub fn tcbdbcmpop(bdb: TCBDB) -> *const u8;
+    // tcbdb.h defines these names as aliases of the tcutil comparators.
+    #[link_name = "tccmplexical"]
+    pub fn tcbdbcmplexical(aptr: *const c_char, asiz: c_int, bptr: *const c_char, bsiz: c_int, op: *const u8) -> c_int;
+    #[link_name = "tccmpdecimal"]
+    pub fn tcbdbcmpdecimal(aptr: *const c_char, asiz: c_int, bptr: *const c_char, bsiz: c_int, op: *const u8) -> c_int;
+    #[link_name = "tccmpint32"]
+    pub fn tcbdbcmpint32(aptr: *const c_char, asiz: c_int, bptr: *const c_char, bsiz: c_int, op: *const u8) -> c_int;
+    #[link_name = "tccmpint64"]
+    pub fn tcbdbcmpint64(aptr: *const c_char, asiz: c_int, bptr: *const c_char, bsiz: c_int, op: *const u8) -> c_int;
+    pub fn tcbdbtune(bdb: TCBDB, lmemb: i32, nmemb: i32,
+                     bnum: i64, apow: i8, fpow: i8, opts: u8) -> bool;
     pub fn tcbdbsetcache(bdb: TCBDB, lcnum: c_int, ncnum: c_int) -> bool;
     pub fn tcbdbsetxmsiz(bdb: TCBDB, xmsiz: c_long) -> bool; // 64bit
     pub fn tcbdbsetdfunit(bdb: TCBDB, dfunit: c_int) -> bool; // 32bit 
@@ -108,9 +126,794 @@ extern {
     pub fn tcbdbput(bdb: TCBDB, kbuf: *const u8, ksiz: c_int, vbuf: *const u8, vsiz: c_int) -> bool;
     pub fn tcbdbputkeep(bdb: TCBDB, kbuf: *const u8, ksiz: c_int, vbuf: *const u8, vsiz: c_int) -> bool;
     pub fn tcbdbout(bdb: TCBDB, kbuf: *const u8, ksiz: c_int) -> bool;
-    pub fn tcbdbget(bdb: TCBDB, kbuf: *const u8, ksiz: c_int, sp: *mut *const c_int) -> *mut u8;
+    pub fn tcbdbget(bdb: TCBDB, kbuf: *const u8, ksiz: c_int, sp: *mut c_int) -> *mut u8;
     pub fn tcbdbsync(bdb: TCBDB) -> bool;
     pub fn tcbdbvanish(bdb: TCBDB) -> bool;
+    pub fn tcbdbputdup(bdb: TCBDB, kbuf: *const u8, ksiz: c_int, vbuf: *const u8, vsiz: c_int) -> bool;
+    pub fn tcbdbputdup3(bdb: TCBDB, kbuf: *const u8, ksiz: c_int, vals: TCLIST) -> bool;
+    pub fn tcbdbget4(bdb: TCBDB, kbuf: *const u8, ksiz: c_int) -> TCLIST;
+    pub fn tcbdbvnum(bdb: TCBDB, kbuf: *const u8, ksiz: c_int) -> c_int;
+    pub fn tcbdbout3(bdb: TCBDB, kbuf: *const u8, ksiz: c_int) -> bool;
+    pub fn tcbdbfwmkeys(bdb: TCBDB, pbuf: *const u8, psiz: c_int, max: c_int) -> TCLIST;
+    pub fn tcbdbrange(bdb: TCBDB, bkbuf: *const u8, bksiz: c_int, binc: bool,
+                      ekbuf: *const u8, eksiz: c_int, einc: bool, max: c_int) -> TCLIST;
+
+    pub fn tcbdbcurnew(bdb: TCBDB) -> TCBDBCUR;
+    pub fn tcbdbcurdel(cur: TCBDBCUR);
+    pub fn tcbdbcurfirst(cur: TCBDBCUR) -> bool;
+    pub fn tcbdbcurlast(cur: TCBDBCUR) -> bool;
+    pub fn tcbdbcurjump(cur: TCBDBCUR, kbuf: *const u8, ksiz: c_int) -> bool;
+    pub fn tcbdbcurjumpback(cur: TCBDBCUR, kbuf: *const u8, ksiz: c_int) -> bool;
+    pub fn tcbdbcurprev(cur: TCBDBCUR) -> bool;
+    pub fn tcbdbcurnext(cur: TCBDBCUR) -> bool;
+    pub fn tcbdbcurput(cur: TCBDBCUR, vbuf: *const u8, vsiz: c_int, cpmode: c_int) -> bool;
+    pub fn tcbdbcurout(cur: TCBDBCUR) -> bool;
+    pub fn tcbdbcurkey(cur: TCBDBCUR, sp: *mut c_int) -> *mut u8;
+    pub fn tcbdbcurval(cur: TCBDBCUR, sp: *mut c_int) -> *mut u8;
+    pub fn tcbdbcurrec(cur: TCBDBCUR, kxstr: TCXSTR, vxstr: TCXSTR) -> bool;
+}
+
+/// BTreeTuning - Tuning applied to a B+ tree database before it is opened.
+///
+/// Parameters left unset keep the Tokyo Cabinet defaults. Out of range
+/// values are rejected with `ErrorCode::Invalid` rather than silently clamped.
+#[derive(Clone, Debug)]
+pub struct BTreeTuning {
+    lmemb: Option<i32>,
+    nmemb: Option<i32>,
+    bnum: Option<i64>,
+    apow: Option<i8>,
+    fpow: Option<i8>,
+    opts: Option<TuningOptions>,
+    lcnum: Option<i32>,
+    ncnum: Option<i32>,
+    xmsiz: Option<i64>,
+    dfunit: Option<i32>,
+}
+
+impl BTreeTuning {
+    /// Tuning with every parameter left at its default.
+    pub fn new() -> BTreeTuning {
+        BTreeTuning {
+            lmemb: None,
+            nmemb: None,
+            bnum: None,
+            apow: None,
+            fpow: None,
+            opts: None,
+            lcnum: None,
+            ncnum: None,
+            xmsiz: None,
+            dfunit: None,
+        }
+    }
+
+    /// Number of members in each leaf page (at least 4).
+    pub fn lmemb(mut self, lmemb: i32) -> BTreeTuning {
+        self.lmemb = Some(lmemb);
+        self
+    }
+
+    /// Number of members in each non-leaf page (at least 4).
+    pub fn nmemb(mut self, nmemb: i32) -> BTreeTuning {
+        self.nmemb = Some(nmemb);
+        self
+    }
+
+    /// Number of elements of the bucket array (positive).
+    pub fn bnum(mut self, bnum: i64) -> BTreeTuning {
+        self.bnum = Some(bnum);
+        self
+    }
+
+    /// Size of record alignment by power of 2 (0 to 16).
+    pub fn apow(mut self, apow: i8) -> BTreeTuning {
+        self.apow = Some(apow);
+        self
+    }
+
+    /// Maximum number of elements of the free block pool by power of 2 (0 to 20).
+    pub fn fpow(mut self, fpow: i8) -> BTreeTuning {
+        self.fpow = Some(fpow);
+        self
+    }
+
+    /// Tuning options.
+    pub fn opts(mut self, opts: TuningOptions) -> BTreeTuning {
+        self.opts = Some(opts);
+        self
+    }
+
+    /// Maximum number of leaf nodes to be cached (positive).
+    pub fn lcnum(mut self, lcnum: i32) -> BTreeTuning {
+        self.lcnum = Some(lcnum);
+        self
+    }
+
+    /// Maximum number of non-leaf nodes to be cached (positive).
+    pub fn ncnum(mut self, ncnum: i32) -> BTreeTuning {
+        self.ncnum = Some(ncnum);
+        self
+    }
+
+    /// Size of the extra mapped memory (0 disables it).
+    pub fn xmsiz(mut self, xmsiz: i64) -> BTreeTuning {
+        self.xmsiz = Some(xmsiz);
+        self
+    }
+
+    /// Unit step number of auto defragmentation (0 disables it).
+    pub fn dfunit(mut self, dfunit: i32) -> BTreeTuning {
+        self.dfunit = Some(dfunit);
+        self
+    }
+
+    /// Check every set parameter is within the range Tokyo Cabinet honours.
+    pub fn validate(&self) -> Result<()> {
+        fn within<T: PartialOrd>(value: Option<T>, min: T, max: T) -> bool {
+            match value {
+                Some(v) => v >= min && v <= max,
+                None => true,
+            }
+        }
+        if within(self.lmemb, 4, i32::MAX) &&
+           within(self.nmemb, 4, i32::MAX) &&
+           within(self.bnum, 1, i64::MAX) &&
+           within(self.apow, 0, 16) &&
+           within(self.fpow, 0, 20) &&
+           within(self.lcnum, 1, i32::MAX) &&
+           within(self.ncnum, 1, i32::MAX) &&
+           within(self.xmsiz, 0, i64::MAX) &&
+           within(self.dfunit, 0, i32::MAX) {
+            Ok(())
+        } else {
+            Err(TcError::new(ErrorCode::Invalid, "tcbdbtune", None))
+        }
+    }
+
+    fn apply(&self, db: &BTreeDb) -> Result<()> {
+        self.validate()?;
+        if self.lmemb.is_some() || self.nmemb.is_some() || self.bnum.is_some() ||
+           self.apow.is_some() || self.fpow.is_some() || self.opts.is_some() {
+            let opts = self.opts.map_or(0, |o| o.bits() as u8);
+            let ok = unsafe {
+                tcbdbtune(db.db, self.lmemb.unwrap_or(-1), self.nmemb.unwrap_or(-1),
+                          self.bnum.unwrap_or(-1), self.apow.unwrap_or(-1),
+                          self.fpow.unwrap_or(-1), opts)
+            };
+            db.check(ok, "tcbdbtune")?;
+        }
+        if self.lcnum.is_some() || self.ncnum.is_some() {
+            let ok = unsafe {
+                tcbdbsetcache(db.db, self.lcnum.unwrap_or(-1), self.ncnum.unwrap_or(-1))
+            };
+            db.check(ok, "tcbdbsetcache")?;
+        }
+        if let Some(xmsiz) = self.xmsiz {
+            db.check(unsafe { tcbdbsetxmsiz(db.db, xmsiz as c_long) }, "tcbdbsetxmsiz")?;
+        }
+        if let Some(dfunit) = self.dfunit {
+            db.check(unsafe { tcbdbsetdfunit(db.db, dfunit) }, "tcbdbsetdfunit")?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for BTreeTuning {
+    fn default() -> BTreeTuning {
+        BTreeTuning::new()
+    }
+}
+
+/// Comparator - The key orders built into Tokyo Cabinet.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Comparator {
+    /// lexical order of the bytes (the default)
+    Lexical,
+    /// order of the decimal numbers the keys spell
+    Decimal,
+    /// order of native 32-bit integers
+    Int32,
+    /// order of native 64-bit integers
+    Int64,
+}
+
+impl Comparator {
+    /// The C comparison function.
+    pub fn as_raw(&self) -> TCCMP {
+        match *self {
+            Comparator::Lexical => tcbdbcmplexical,
+            Comparator::Decimal => tcbdbcmpdecimal,
+            Comparator::Int32 => tcbdbcmpint32,
+            Comparator::Int64 => tcbdbcmpint64,
+        }
+    }
+}
+
+type CmpFn = dyn Fn(&[u8], &[u8]) -> Ordering;
+
+#[derive(Clone)]
+enum KeyOrder {
+    Builtin(Comparator),
+    Custom(Rc<CmpFn>),
+}
+
+/// BTreeDbBuilder - Collects the open mode and tuning for a `BTreeDb`.
+#[derive(Clone)]
+pub struct BTreeDbBuilder {
+    modes: OpenModes,
+    tuning: BTreeTuning,
+    order: Option<KeyOrder>,
+}
+
+impl BTreeDbBuilder {
+    /// A builder opening the database as a reader with default tuning.
+    pub fn new() -> BTreeDbBuilder {
+        BTreeDbBuilder {
+            modes: BDBOREADER,
+            tuning: BTreeTuning::new(),
+            order: None,
+        }
+    }
+
+    /// Set the open modes.
+    pub fn modes(mut self, modes: OpenModes) -> BTreeDbBuilder {
+        self.modes = modes;
+        self
+    }
+
+    /// Set the tuning applied before opening.
+    pub fn tuning(mut self, tuning: BTreeTuning) -> BTreeDbBuilder {
+        self.tuning = tuning;
+        self
+    }
+
+    /// Tune the database before it is created (see `tcbdbtune`).
+    ///
+    /// Shorthand for the matching `BTreeTuning` setters.
+    pub fn tune(mut self, lmemb: i32, nmemb: i32, bnum: i64,
+                apow: i8, fpow: i8, opts: TuningOptions) -> BTreeDbBuilder {
+        self.tuning = self.tuning.lmemb(lmemb).nmemb(nmemb).bnum(bnum)
+            .apow(apow).fpow(fpow).opts(opts);
+        self
+    }
+
+    /// Set the number of cached leaf and non-leaf nodes (see `tcbdbsetcache`).
+    ///
+    /// Shorthand for `BTreeTuning::lcnum` and `BTreeTuning::ncnum`.
+    pub fn cache(mut self, lcnum: i32, ncnum: i32) -> BTreeDbBuilder {
+        self.tuning = self.tuning.lcnum(lcnum).ncnum(ncnum);
+        self
+    }
+
+    /// Order keys with a built-in comparator.
+    ///
+    /// A database must always be opened with the comparator it was created with.
+    pub fn comparator(mut self, cmp: Comparator) -> BTreeDbBuilder {
+        self.order = Some(KeyOrder::Builtin(cmp));
+        self
+    }
+
+    /// Order keys with a Rust function, kept alive by the opened `BTreeDb`.
+    ///
+    /// A database must always be opened with the comparator it was created with.
+    /// `cmp` must not panic: a panic while Tokyo Cabinet is comparing keys
+    /// aborts the process rather than risk a corrupted tree.
+    pub fn compare_with<F>(mut self, cmp: F) -> BTreeDbBuilder
+        where F: Fn(&[u8], &[u8]) -> Ordering + 'static
+    {
+        self.order = Some(KeyOrder::Custom(Rc::new(cmp)));
+        self
+    }
+
+    /// Open the database file at `path`.
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<BTreeDb> {
+        let path = path.as_ref();
+        let cpath = path_to_cstring(path, "tcbdbopen")?;
+        let mut db = BTreeDb { db: unsafe { tcbdbnew() }, path: path.to_path_buf(), cmp: None };
+        self.tuning.apply(&db)?;
+        match self.order {
+            Some(KeyOrder::Builtin(cmp)) => {
+                let ok = unsafe { tcbdbsetcmpfunc(db.db, cmp.as_raw(), ptr::null()) };
+                db.check(ok, "tcbdbsetcmpfunc")?;
+            }
+            Some(KeyOrder::Custom(ref f)) => {
+                let f = f.clone();
+                let cmp: Box<Box<CmpFn>> = Box::new(Box::new(move |a: &[u8], b: &[u8]| f(a, b)));
+                let (func, op) = cmp_callback(&*cmp);
+                db.cmp = Some(cmp);
+                let ok = unsafe { tcbdbsetcmpfunc(db.db, func, op) };
+                db.check(ok, "tcbdbsetcmpfunc")?;
+            }
+            None => {}
+        }
+        let ok = unsafe { tcbdbopen(db.db, cpath.as_ptr(), self.modes.bits()) };
+        db.check(ok, "tcbdbopen")?;
+        Ok(db)
+    }
+}
+
+impl Default for BTreeDbBuilder {
+    fn default() -> BTreeDbBuilder {
+        BTreeDbBuilder::new()
+    }
+}
+
+/// BTreeDb - An owned B+ tree database.
+///
+/// The underlying `TCBDB` is closed and deleted when the `BTreeDb` is dropped.
+pub struct BTreeDb {
+    db: TCBDB,
+    path: PathBuf,
+    // Boxed twice so the address handed to `tcbdbsetcmpfunc` is stable.
+    cmp: Option<Box<Box<CmpFn>>>,
+}
+
+impl BTreeDb {
+    /// Start configuring a database to open.
+    pub fn builder() -> BTreeDbBuilder {
+        BTreeDbBuilder::new()
+    }
+
+    /// Open the database file at `path` with default tuning.
+    pub fn open<P: AsRef<Path>>(path: P, modes: OpenModes) -> Result<BTreeDb> {
+        BTreeDbBuilder::new().modes(modes).open(path)
+    }
+
+    /// Store a record, overwriting any existing value.
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let ok = unsafe {
+            tcbdbput(self.db, key.as_ptr(), key.len() as c_int, value.as_ptr(), value.len() as c_int)
+        };
+        self.check(ok, "tcbdbput")
+    }
+
+    /// Store a record unless the key already exists.
+    ///
+    /// Returns `false` if an existing record was kept.
+    pub fn put_keep(&mut self, key: &[u8], value: &[u8]) -> Result<bool> {
+        let ok = unsafe {
+            tcbdbputkeep(self.db, key.as_ptr(), key.len() as c_int, value.as_ptr(), value.len() as c_int)
+        };
+        self.check_found(ok, "tcbdbputkeep", ErrorCode::Keep)
+    }
+
+    /// Retrieve the value of a record.
+    pub fn get(&self, key: &[u8]) -> Result<Option<TcBytes>> {
+        let mut size: c_int = 0;
+        let vbuf = unsafe { tcbdbget(self.db, key.as_ptr(), key.len() as c_int, &mut size) };
+        if vbuf.is_null() {
+            return self.check_found(false, "tcbdbget", ErrorCode::NoRec).map(|_| None);
+        }
+        Ok(Some(unsafe { TcBytes::from_raw(vbuf, size as usize) }))
+    }
+
+    /// Remove a record.
+    ///
+    /// Returns `false` if there was no such record.
+    pub fn remove(&mut self, key: &[u8]) -> Result<bool> {
+        let ok = unsafe { tcbdbout(self.db, key.as_ptr(), key.len() as c_int) };
+        self.check_found(ok, "tcbdbout", ErrorCode::NoRec)
+    }
+
+    /// Store a record, appending `value` after any existing values of the key.
+    pub fn put_dup(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let ok = unsafe {
+            tcbdbputdup(self.db, key.as_ptr(), key.len() as c_int, value.as_ptr(), value.len() as c_int)
+        };
+        self.check(ok, "tcbdbputdup")
+    }
+
+    /// Store records, appending each of `values` after any existing values of the key.
+    pub fn put_dups<V: AsRef<[u8]>>(&mut self, key: &[u8], values: &[V]) -> Result<()> {
+        let ok = unsafe {
+            let vals = list_from_slices(values);
+            let ok = tcbdbputdup3(self.db, key.as_ptr(), key.len() as c_int, vals);
+            tclistdel(vals);
+            ok
+        };
+        self.check(ok, "tcbdbputdup3")
+    }
+
+    /// Retrieve every value of a key, in insertion order.
+    ///
+    /// Returns an empty `Vec` if there is no such record.
+    pub fn get_all(&self, key: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let vals = unsafe { tcbdbget4(self.db, key.as_ptr(), key.len() as c_int) };
+        if vals.0.is_null() {
+            return self.check_found(false, "tcbdbget4", ErrorCode::NoRec).map(|_| Vec::new());
+        }
+        unsafe {
+            let values = list_to_vec(vals);
+            tclistdel(vals);
+            Ok(values)
+        }
+    }
+
+    /// The number of values stored under a key.
+    pub fn value_count(&self, key: &[u8]) -> Result<usize> {
+        let vnum = unsafe { tcbdbvnum(self.db, key.as_ptr(), key.len() as c_int) };
+        if vnum == 0 {
+            self.check_found(false, "tcbdbvnum", ErrorCode::NoRec)?;
+        }
+        Ok(vnum as usize)
+    }
+
+    /// Remove every value of a key.
+    ///
+    /// Returns `false` if there was no such record.
+    pub fn remove_all(&mut self, key: &[u8]) -> Result<bool> {
+        let ok = unsafe { tcbdbout3(self.db, key.as_ptr(), key.len() as c_int) };
+        self.check_found(ok, "tcbdbout3", ErrorCode::NoRec)
+    }
+
+    /// The keys beginning with `prefix` in key order, at most `max` of them if given.
+    pub fn keys_with_prefix(&self, prefix: &[u8], max: Option<usize>) -> Result<Vec<Vec<u8>>> {
+        let max = max.map_or(-1, |m| cmp::min(m, c_int::MAX as usize) as c_int);
+        unsafe {
+            let keys = tcbdbfwmkeys(self.db, prefix.as_ptr(), prefix.len() as c_int, max);
+            let result = list_to_vec(keys);
+            tclistdel(keys);
+            Ok(result)
+        }
+    }
+
+    /// Synchronize updated contents with the file and the device.
+    pub fn sync(&mut self) -> Result<()> {
+        let ok = unsafe { tcbdbsync(self.db) };
+        self.check(ok, "tcbdbsync")
+    }
+
+    /// Remove all records.
+    pub fn vanish(&mut self) -> Result<()> {
+        let ok = unsafe { tcbdbvanish(self.db) };
+        self.check(ok, "tcbdbvanish")
+    }
+
+    /// A read-only cursor over the records, initially unpositioned.
+    pub fn cursor(&self) -> Cursor<'_> {
+        Cursor { cur: unsafe { tcbdbcurnew(self.db) }, db: self }
+    }
+
+    /// A cursor that can also store and remove records, initially unpositioned.
+    pub fn cursor_mut(&mut self) -> CursorMut<'_> {
+        CursorMut { cursor: self.cursor() }
+    }
+
+    /// The records whose keys fall within `range`, in key order.
+    ///
+    /// Bounds are checked with the database's comparator. Only `..` and
+    /// `(Bound<&[u8]>, Bound<&[u8]>)` implement `RangeBounds<[u8]>`, so
+    /// ranges are written as, for example,
+    /// `db.range((Bound::Included(&b"a"[..]), Bound::Excluded(&b"m"[..])))`.
+    pub fn range<R: RangeBounds<[u8]>>(&self, range: R) -> Range<'_> {
+        fn owned(b: Bound<&[u8]>) -> Bound<Vec<u8>> {
+            match b {
+                Bound::Included(k) => Bound::Included(k.to_vec()),
+                Bound::Excluded(k) => Bound::Excluded(k.to_vec()),
+                Bound::Unbounded => Bound::Unbounded,
+            }
+        }
+        Range {
+            db: self,
+            front: self.cursor(),
+            back: self.cursor(),
+            front_at: None,
+            back_at: None,
+            start: owned(range.start_bound()),
+            end: owned(range.end_bound()),
+            done: false,
+        }
+    }
+
+    /// Close the database, reporting any error raised while doing so.
+    pub fn close(self) -> Result<()> {
+        let ok = unsafe { tcbdbclose(self.db) };
+        self.check(ok, "tcbdbclose")
+    }
+
+    /// The raw handle, for calling functions not wrapped here.
+    pub fn as_raw(&self) -> TCBDB {
+        self.db
+    }
+
+    // Order two keys as the database does.
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        let ord = unsafe {
+            let cmp = tcbdbcmpfunc(self.db);
+            cmp(a.as_ptr() as *const c_char, a.len() as c_int,
+                b.as_ptr() as *const c_char, b.len() as c_int, tcbdbcmpop(self.db))
+        };
+        ord.cmp(&0)
+    }
+
+    fn check(&self, ok: bool, op: &'static str) -> Result<()> {
+        tcutil::check(tcbdbecode, tcbdberrmsg, self.db, &self.path, ok, op)
+    }
+
+    fn check_found(&self, ok: bool, op: &'static str, benign: ErrorCode) -> Result<bool> {
+        tcutil::check_found(tcbdbecode, tcbdberrmsg, self.db, &self.path, ok, op, benign)
+    }
+}
+
+impl Drop for BTreeDb {
+    fn drop(&mut self) {
+        unsafe { tcbdbdel(self.db) };
+    }
+}
+
+/// Range - The records of a `BTreeDb` within a key range.
+///
+/// Each end walks its own cursor through every record, duplicates included,
+/// and the two stop where their positions meet.
+pub struct Range<'db> {
+    db: &'db BTreeDb,
+    front: Cursor<'db>,
+    back: Cursor<'db>,
+    // The record each cursor stands on, as its key and the index of the
+    // value among the key's duplicates; `None` until the cursor is placed.
+    front_at: Option<(Vec<u8>, usize)>,
+    back_at: Option<(Vec<u8>, usize)>,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    done: bool,
+}
+
+impl<'db> Range<'db> {
+    // `Less` before the start bound, `Greater` past the end bound.
+    fn locate(&self, key: &[u8]) -> Ordering {
+        let after_start = match self.start {
+            Bound::Included(ref k) => self.db.compare(key, k) != Ordering::Less,
+            Bound::Excluded(ref k) => self.db.compare(key, k) == Ordering::Greater,
+            Bound::Unbounded => true,
+        };
+        let before_end = match self.end {
+            Bound::Included(ref k) => self.db.compare(key, k) != Ordering::Greater,
+            Bound::Excluded(ref k) => self.db.compare(key, k) == Ordering::Less,
+            Bound::Unbounded => true,
+        };
+        if !after_start {
+            Ordering::Less
+        } else if !before_end {
+            Ordering::Greater
+        } else {
+            Ordering::Equal
+        }
+    }
+
+    fn order(&self, a: &(Vec<u8>, usize), b: &(Vec<u8>, usize)) -> Ordering {
+        self.db.compare(&a.0, &b.0).then(a.1.cmp(&b.1))
+    }
+
+    fn step_front(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let mut moved = match (&self.front_at, &self.start) {
+            (&Some(_), _) => self.front.move_next()?,
+            (&None, &Bound::Included(ref k)) | (&None, &Bound::Excluded(ref k)) => self.front.jump(k)?,
+            (&None, &Bound::Unbounded) => self.front.first()?,
+        };
+        while moved {
+            let (key, value) = match self.front.record()? {
+                Some(record) => record,
+                None => break,
+            };
+            // Moving forward onto a new key lands on its first duplicate.
+            let dup = match self.front_at {
+                Some((ref k, dup)) if self.db.compare(k, &key) == Ordering::Equal => dup + 1,
+                _ => 0,
+            };
+            let at = (key, dup);
+            match self.locate(&at.0) {
+                Ordering::Less => moved = self.front.move_next()?,
+                Ordering::Greater => break,
+                Ordering::Equal => {
+                    let behind = match self.back_at {
+                        Some(ref back) => self.order(&at, back) == Ordering::Less,
+                        None => true,
+                    };
+                    let record = if behind { Some((at.0.clone(), value)) } else { None };
+                    self.front_at = Some(at);
+                    return Ok(record);
+                }
+            }
+            self.front_at = Some(at);
+        }
+        Ok(None)
+    }
+
+    fn step_back(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let mut moved = match (&self.back_at, &self.end) {
+            (&Some(_), _) => self.back.move_prev()?,
+            (&None, &Bound::Included(ref k)) | (&None, &Bound::Excluded(ref k)) => self.back.jump_back(k)?,
+            (&None, &Bound::Unbounded) => self.back.last()?,
+        };
+        while moved {
+            let (key, value) = match self.back.record()? {
+                Some(record) => record,
+                None => break,
+            };
+            // Jumping back or moving back onto a new key lands on its last duplicate.
+            let dup = match self.back_at {
+                Some((ref k, dup)) if self.db.compare(k, &key) == Ordering::Equal => dup - 1,
+                _ => self.db.value_count(&key)? - 1,
+            };
+            let at = (key, dup);
+            match self.locate(&at.0) {
+                Ordering::Greater => moved = self.back.move_prev()?,
+                Ordering::Less => break,
+                Ordering::Equal => {
+                    let ahead = match self.front_at {
+                        Some(ref front) => self.order(&at, front) == Ordering::Greater,
+                        None => true,
+                    };
+                    let record = if ahead { Some((at.0.clone(), value)) } else { None };
+                    self.back_at = Some(at);
+                    return Ok(record);
+                }
+            }
+            self.back_at = Some(at);
+        }
+        Ok(None)
+    }
+
+    fn finish(&mut self, item: Result<Option<(Vec<u8>, Vec<u8>)>>) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+        let item = item.transpose();
+        self.done = !matches!(item, Some(Ok(_)));
+        item
+    }
+}
+
+impl<'db> Iterator for Range<'db> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let item = self.step_front();
+        self.finish(item)
+    }
+}
+
+impl<'db> DoubleEndedIterator for Range<'db> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let item = self.step_back();
+        self.finish(item)
+    }
+}
+
+/// Cursor - A position in the key order of a `BTreeDb`.
+///
+/// Moves return `false` once the cursor runs off either end of the database.
+/// A `Cursor` only reads; `BTreeDb::cursor_mut` gives a `CursorMut` that writes.
+pub struct Cursor<'db> {
+    cur: TCBDBCUR,
+    db: &'db BTreeDb,
+}
+
+impl<'db> Cursor<'db> {
+    /// Move to the first record.
+    pub fn first(&mut self) -> Result<bool> {
+        let ok = unsafe { tcbdbcurfirst(self.cur) };
+        self.db.check_found(ok, "tcbdbcurfirst", ErrorCode::NoRec)
+    }
+
+    /// Move to the last record.
+    pub fn last(&mut self) -> Result<bool> {
+        let ok = unsafe { tcbdbcurlast(self.cur) };
+        self.db.check_found(ok, "tcbdbcurlast", ErrorCode::NoRec)
+    }
+
+    /// Move to the first record whose key is not less than `key`.
+    pub fn jump(&mut self, key: &[u8]) -> Result<bool> {
+        let ok = unsafe { tcbdbcurjump(self.cur, key.as_ptr(), key.len() as c_int) };
+        self.db.check_found(ok, "tcbdbcurjump", ErrorCode::NoRec)
+    }
+
+    /// Move to the last record whose key is not greater than `key`.
+    pub fn jump_back(&mut self, key: &[u8]) -> Result<bool> {
+        let ok = unsafe { tcbdbcurjumpback(self.cur, key.as_ptr(), key.len() as c_int) };
+        self.db.check_found(ok, "tcbdbcurjumpback", ErrorCode::NoRec)
+    }
+
+    /// Move to the previous record.
+    pub fn move_prev(&mut self) -> Result<bool> {
+        let ok = unsafe { tcbdbcurprev(self.cur) };
+        self.db.check_found(ok, "tcbdbcurprev", ErrorCode::NoRec)
+    }
+
+    /// Move to the next record.
+    pub fn move_next(&mut self) -> Result<bool> {
+        let ok = unsafe { tcbdbcurnext(self.cur) };
+        self.db.check_found(ok, "tcbdbcurnext", ErrorCode::NoRec)
+    }
+
+    /// The key of the current record, or `None` if the cursor is unpositioned.
+    pub fn key(&self) -> Result<Option<TcBytes>> {
+        let mut size: c_int = 0;
+        let kbuf = unsafe { tcbdbcurkey(self.cur, &mut size) };
+        if kbuf.is_null() {
+            return self.db.check_found(false, "tcbdbcurkey", ErrorCode::NoRec).map(|_| None);
+        }
+        Ok(Some(unsafe { TcBytes::from_raw(kbuf, size as usize) }))
+    }
+
+    /// The value of the current record, or `None` if the cursor is unpositioned.
+    pub fn value(&self) -> Result<Option<TcBytes>> {
+        let mut size: c_int = 0;
+        let vbuf = unsafe { tcbdbcurval(self.cur, &mut size) };
+        if vbuf.is_null() {
+            return self.db.check_found(false, "tcbdbcurval", ErrorCode::NoRec).map(|_| None);
+        }
+        Ok(Some(unsafe { TcBytes::from_raw(vbuf, size as usize) }))
+    }
+
+    /// The key and value of the current record, or `None` if the cursor is unpositioned.
+    pub fn record(&self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        unsafe {
+            let kxstr = tcxstrnew();
+            let vxstr = tcxstrnew();
+            let ok = tcbdbcurrec(self.cur, kxstr, vxstr);
+            let record = if ok { Some((xstr_to_vec(kxstr), xstr_to_vec(vxstr))) } else { None };
+            tcxstrdel(kxstr);
+            tcxstrdel(vxstr);
+            match record {
+                Some(record) => Ok(Some(record)),
+                None => self.db.check_found(false, "tcbdbcurrec", ErrorCode::NoRec).map(|_| None),
+            }
+        }
+    }
+
+}
+
+impl<'db> Drop for Cursor<'db> {
+    fn drop(&mut self) {
+        unsafe { tcbdbcurdel(self.cur) };
+    }
+}
+
+/// CursorMut - A `Cursor` holding the `BTreeDb` mutably, so it can also write.
+///
+/// Movement and reads are those of `Cursor`, through `Deref`.
+pub struct CursorMut<'db> {
+    cursor: Cursor<'db>,
+}
+
+impl<'db> CursorMut<'db> {
+    /// Store `value` relative to the current record.
+    ///
+    /// `BDBCPCURRENT` overwrites the current value; `BDBCPBEFORE` and
+    /// `BDBCPAFTER` insert a duplicate of the current key around it.
+    pub fn put(&mut self, value: &[u8], mode: CursorPutMode) -> Result<()> {
+        let ok = unsafe {
+            tcbdbcurput(self.cursor.cur, value.as_ptr(), value.len() as c_int, mode.bits())
+        };
+        self.cursor.db.check(ok, "tcbdbcurput")
+    }
+
+    /// Remove the current record, moving the cursor to the next one.
+    ///
+    /// Returns `false` if the cursor is unpositioned.
+    pub fn remove(&mut self) -> Result<bool> {
+        let ok = unsafe { tcbdbcurout(self.cursor.cur) };
+        self.cursor.db.check_found(ok, "tcbdbcurout", ErrorCode::NoRec)
+    }
+}
+
+impl<'db> Deref for CursorMut<'db> {
+    type Target = Cursor<'db>;
+
+    fn deref(&self) -> &Cursor<'db> {
+        &self.cursor
+    }
+}
+
+impl<'db> DerefMut for CursorMut<'db> {
+    fn deref_mut(&mut self) -> &mut Cursor<'db> {
+        &mut self.cursor
+    }
 }
 
 #[cfg(test)]
@@ -120,6 +923,9 @@ mod test {
     use tcutil::*;
     use std::slice;
     use std::ffi::{CStr, CString};
+    use std::cell::Cell;
+    use std::ops::Bound;
+    use std::rc::Rc;
 
     #[test]
     fn test_new_del() {
@@ -159,9 +965,8 @@ mod test {
             let k = b"hello";
             let v = b"world";
             assert!(tcbdbput(db, k.clone().as_ptr(), k.len() as c_int, v.clone().as_ptr(), v.len() as c_int));
-            let mut v2_sz: *const i32 = 0 as *const i32;
-            let v2_sz_ptr: *mut *const i32 = &mut v2_sz;
-            let v2 = tcbdbget(db, k.as_ptr(), k.len() as c_int, v2_sz_ptr);
+            let mut v2_sz: c_int = 0;
+            let v2 = tcbdbget(db, k.as_ptr(), k.len() as c_int, &mut v2_sz);
             assert!(!v2.is_null());
             assert_eq!(v.len(), v2_sz as usize);
             let v2_slice = slice::from_raw_parts(v2, v2_sz as usize);
@@ -173,4 +978,265 @@ mod test {
             assert!(::std::fs::remove_file(rustpath).is_ok());
         }
     }
+
+    #[test]
+    fn test_btreedb_basics() {
+        let rustpath = ".tcbdb_test_btreedb_basics.tcb";
+        {
+            let mut db = BTreeDb::builder()
+                .modes(BDBOWRITER | BDBOCREAT | BDBOTRUNC)
+                .tuning(BTreeTuning::new().lmemb(64).nmemb(128).bnum(1024).apow(4).fpow(8)
+                        .opts(BDBTLARGE).lcnum(256).ncnum(128).xmsiz(1 << 20).dfunit(8))
+                .open(rustpath)
+                .unwrap();
+            db.put(b"hello", b"world").unwrap();
+            assert_eq!(db.get(b"hello").unwrap().unwrap(), &b"world"[..]);
+            assert!(db.get(b"missing").unwrap().is_none());
+            assert!(!db.put_keep(b"hello", b"there").unwrap());
+            assert!(db.put_keep(b"hi", b"there").unwrap());
+            assert!(db.remove(b"hello").unwrap());
+            assert!(!db.remove(b"hello").unwrap());
+            db.sync().unwrap();
+            db.vanish().unwrap();
+            assert!(db.get(b"hi").unwrap().is_none());
+            db.close().unwrap();
+        }
+        assert!(::std::fs::remove_file(rustpath).is_ok());
+    }
+
+    #[test]
+    fn test_btreedb_open_missing_file() {
+        let err = BTreeDb::open(".tcbdb_test_btreedb_missing.tcb", BDBOREADER).err().unwrap();
+        assert_eq!(err.code(), ErrorCode::NoFile);
+        assert_eq!(err.op(), "tcbdbopen");
+    }
+
+    #[test]
+    fn test_btreetuning_validate() {
+        assert!(BTreeTuning::new().validate().is_ok());
+        assert!(BTreeTuning::new().lmemb(4).apow(16).fpow(20).validate().is_ok());
+        assert_eq!(BTreeTuning::new().lmemb(2).validate().err().map(|e| e.code()), Some(ErrorCode::Invalid));
+        assert_eq!(BTreeTuning::new().apow(17).validate().err().map(|e| e.code()), Some(ErrorCode::Invalid));
+        assert_eq!(BTreeTuning::new().fpow(-1).validate().err().map(|e| e.code()), Some(ErrorCode::Invalid));
+        assert_eq!(BTreeTuning::new().bnum(0).validate().err().map(|e| e.code()), Some(ErrorCode::Invalid));
+        let err = BTreeDb::builder()
+            .modes(BDBOWRITER | BDBOCREAT)
+            .tuning(BTreeTuning::new().ncnum(0))
+            .open(".tcbdb_test_btreetuning_invalid.tcb")
+            .err()
+            .map(|e| e.code());
+        assert_eq!(err, Some(ErrorCode::Invalid));
+        let err = BTreeDb::builder()
+            .modes(BDBOWRITER | BDBOCREAT)
+            .tune(64, 128, 1024, 4, 8, BDBTLARGE)
+            .cache(0, 128)
+            .open(".tcbdb_test_btreetuning_invalid.tcb")
+            .err()
+            .map(|e| e.code());
+        assert_eq!(err, Some(ErrorCode::Invalid));
+    }
+
+    #[test]
+    fn test_btreedb_cursor() {
+        let rustpath = ".tcbdb_test_btreedb_cursor.tcb";
+        {
+            let mut db = BTreeDb::open(rustpath, BDBOWRITER | BDBOCREAT | BDBOTRUNC).unwrap();
+            for &(k, v) in &[(b"b", b"2"), (b"d", b"4"), (b"a", b"1"), (b"c", b"3")] {
+                db.put(k, v).unwrap();
+            }
+            let mut cur = db.cursor();
+            assert!(cur.key().unwrap().is_none());
+            assert!(cur.first().unwrap());
+            let mut keys = Vec::new();
+            loop {
+                keys.push(cur.key().unwrap().unwrap().into_vec());
+                if !cur.move_next().unwrap() {
+                    break;
+                }
+            }
+            assert_eq!(keys, vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec(), b"d".to_vec()]);
+
+            assert!(cur.jump(b"bb").unwrap());
+            assert_eq!(cur.record().unwrap(), Some((b"c".to_vec(), b"3".to_vec())));
+            drop(cur);
+
+            let mut cur = db.cursor_mut();
+            assert!(cur.jump_back(b"bb").unwrap());
+            assert_eq!(cur.value().unwrap().unwrap(), &b"2"[..]);
+            cur.put(b"two", BDBCPCURRENT).unwrap();
+            assert!(cur.remove().unwrap());
+            assert_eq!(cur.key().unwrap().unwrap(), &b"c"[..]);
+            assert!(cur.last().unwrap());
+            assert!(cur.move_prev().unwrap());
+            assert_eq!(cur.key().unwrap().unwrap(), &b"c"[..]);
+            assert!(!cur.jump(b"e").unwrap());
+        }
+        assert!(::std::fs::remove_file(rustpath).is_ok());
+    }
+
+    #[test]
+    fn test_btreedb_range() {
+        let rustpath = ".tcbdb_test_btreedb_range.tcb";
+        {
+            let mut db = BTreeDb::open(rustpath, BDBOWRITER | BDBOCREAT | BDBOTRUNC).unwrap();
+            for k in &[b"a", b"b", b"c", b"d", b"e"] {
+                db.put(*k, *k).unwrap();
+            }
+            let keys = |range: Range| -> Vec<Vec<u8>> {
+                range.map(|r| r.unwrap().0).collect()
+            };
+            assert_eq!(keys(db.range(..)).len(), 5);
+            assert_eq!(keys(db.range((Bound::Included(&b"b"[..]), Bound::Excluded(&b"d"[..])))),
+                       vec![b"b".to_vec(), b"c".to_vec()]);
+            assert_eq!(keys(db.range((Bound::Excluded(&b"b"[..]), Bound::Unbounded))),
+                       vec![b"c".to_vec(), b"d".to_vec(), b"e".to_vec()]);
+            let rev: Vec<_> = db.range((Bound::Unbounded, Bound::Included(&b"c"[..])))
+                .rev().take(2).map(|r| r.unwrap()).collect();
+            assert_eq!(rev, vec![(b"c".to_vec(), b"c".to_vec()), (b"b".to_vec(), b"b".to_vec())]);
+            let mut both = db.range(..);
+            assert_eq!(both.next().unwrap().unwrap().0, b"a");
+            assert_eq!(both.next_back().unwrap().unwrap().0, b"e");
+            assert_eq!(both.count(), 3);
+
+            db.put_dup(b"c", b"c2").unwrap();
+            db.put_dup(b"e", b"e2").unwrap();
+            db.put_dup(b"e", b"e3").unwrap();
+            fn values<I: Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>>(range: I) -> Vec<Vec<u8>> {
+                range.map(|r| r.unwrap().1).collect()
+            }
+            let all: Vec<Vec<u8>> = [&b"a"[..], b"b", b"c", b"c2", b"d", b"e", b"e2", b"e3"].iter()
+                .map(|v| v.to_vec()).collect();
+            assert_eq!(values(db.range(..)), all);
+            let mut rev = values(db.range(..).rev());
+            rev.reverse();
+            assert_eq!(rev, all);
+            assert_eq!(values(db.range((Bound::Included(&b"c"[..]), Bound::Included(&b"c"[..])))),
+                       vec![b"c".to_vec(), b"c2".to_vec()]);
+            assert_eq!(values(db.range((Bound::Excluded(&b"d"[..]), Bound::Unbounded)).rev()),
+                       vec![b"e3".to_vec(), b"e2".to_vec(), b"e".to_vec()]);
+
+            // The ends meet between two duplicates of the same key.
+            let mut both = db.range((Bound::Included(&b"e"[..]), Bound::Unbounded));
+            assert_eq!(both.next_back().unwrap().unwrap(), (b"e".to_vec(), b"e3".to_vec()));
+            assert_eq!(both.next().unwrap().unwrap(), (b"e".to_vec(), b"e".to_vec()));
+            assert_eq!(both.next_back().unwrap().unwrap(), (b"e".to_vec(), b"e2".to_vec()));
+            assert!(both.next().is_none());
+            assert!(both.next_back().is_none());
+            let mut both = db.range((Bound::Included(&b"b"[..]), Bound::Included(&b"c"[..])));
+            assert_eq!(both.next_back().unwrap().unwrap(), (b"c".to_vec(), b"c2".to_vec()));
+            assert_eq!(both.next().unwrap().unwrap(), (b"b".to_vec(), b"b".to_vec()));
+            assert_eq!(both.next().unwrap().unwrap(), (b"c".to_vec(), b"c".to_vec()));
+            assert!(both.next_back().is_none());
+        }
+        assert!(::std::fs::remove_file(rustpath).is_ok());
+    }
+
+    #[test]
+    fn test_btreedb_duplicates() {
+        let rustpath = ".tcbdb_test_btreedb_duplicates.tcb";
+        {
+            let mut db = BTreeDb::open(rustpath, BDBOWRITER | BDBOCREAT | BDBOTRUNC).unwrap();
+            db.put_dup(b"tag", b"red").unwrap();
+            db.put_dup(b"tag", b"green").unwrap();
+            db.put_dups(b"tag", &[&b"blue"[..], &b"red"[..]]).unwrap();
+            db.put(b"other", b"x").unwrap();
+            assert_eq!(db.value_count(b"tag").unwrap(), 4);
+            assert_eq!(db.value_count(b"missing").unwrap(), 0);
+            assert_eq!(db.get_all(b"tag").unwrap(),
+                       vec![b"red".to_vec(), b"green".to_vec(), b"blue".to_vec(), b"red".to_vec()]);
+            assert_eq!(db.get(b"tag").unwrap().unwrap(), &b"red"[..]);
+            assert!(db.get_all(b"missing").unwrap().is_empty());
+            assert!(db.remove_all(b"tag").unwrap());
+            assert!(!db.remove_all(b"tag").unwrap());
+            assert_eq!(db.value_count(b"tag").unwrap(), 0);
+            assert_eq!(db.value_count(b"other").unwrap(), 1);
+        }
+        assert!(::std::fs::remove_file(rustpath).is_ok());
+    }
+
+    #[test]
+    fn test_btreedb_keys_with_prefix() {
+        let rustpath = ".tcbdb_test_btreedb_keys_with_prefix.tcb";
+        {
+            let mut db = BTreeDb::open(rustpath, BDBOWRITER | BDBOCREAT | BDBOTRUNC).unwrap();
+            for k in &[&b"user:2"[..], b"user:1", b"group:1", b"users"] {
+                db.put(k, b"").unwrap();
+            }
+            assert_eq!(db.keys_with_prefix(b"user:", None).unwrap(),
+                       vec![b"user:1".to_vec(), b"user:2".to_vec()]);
+            assert_eq!(db.keys_with_prefix(b"user", Some(2)).unwrap(),
+                       vec![b"user:1".to_vec(), b"user:2".to_vec()]);
+        }
+        assert!(::std::fs::remove_file(rustpath).is_ok());
+    }
+
+    #[test]
+    fn test_btreedb_comparators() {
+        let rustpath = ".tcbdb_test_btreedb_comparators.tcb";
+        {
+            let mut db = BTreeDb::builder()
+                .modes(BDBOWRITER | BDBOCREAT | BDBOTRUNC)
+                .comparator(Comparator::Decimal)
+                .open(rustpath)
+                .unwrap();
+            for k in &[&b"100"[..], b"9", b"10"] {
+                db.put(k, b"").unwrap();
+            }
+            let keys: Vec<_> = db.range(..).map(|r| r.unwrap().0).collect();
+            assert_eq!(keys, vec![b"9".to_vec(), b"10".to_vec(), b"100".to_vec()]);
+        }
+        assert!(::std::fs::remove_file(rustpath).is_ok());
+        {
+            let mut db = BTreeDb::builder()
+                .modes(BDBOWRITER | BDBOCREAT | BDBOTRUNC)
+                .compare_with(|a: &[u8], b: &[u8]| b.cmp(a))
+                .open(rustpath)
+                .unwrap();
+            for k in &[b"a", b"c", b"b"] {
+                db.put(*k, b"").unwrap();
+            }
+            let keys: Vec<_> = db.range(..).map(|r| r.unwrap().0).collect();
+            assert_eq!(keys, vec![b"c".to_vec(), b"b".to_vec(), b"a".to_vec()]);
+            assert_eq!(db.keys_with_prefix(b"b", None).unwrap(), vec![b"b".to_vec()]);
+        }
+        assert!(::std::fs::remove_file(rustpath).is_ok());
+    }
+
+    #[test]
+    fn test_btreedb_compare_with_outlives_close() {
+        let rustpath = ".tcbdb_test_btreedb_compare_with.tcb";
+        let calls = Rc::new(Cell::new(0));
+        let counter = calls.clone();
+        let reverse = move |a: &[u8], b: &[u8]| {
+            counter.set(counter.get() + 1);
+            b.cmp(a)
+        };
+        {
+            let mut db = BTreeDb::builder()
+                .modes(BDBOWRITER | BDBOCREAT | BDBOTRUNC)
+                .compare_with(reverse.clone())
+                .open(rustpath)
+                .unwrap();
+            // The builder is gone; only the database keeps the closure alive.
+            assert!(db.cmp.is_some());
+            assert_eq!(Rc::strong_count(&calls), 3);
+            for i in 0..1000 {
+                db.put(format!("{:04}", i).as_bytes(), b"").unwrap();
+            }
+            assert!(calls.get() > 0);
+            db.close().unwrap();
+            assert_eq!(Rc::strong_count(&calls), 2);
+        }
+        {
+            let db = BTreeDb::builder()
+                .modes(BDBOREADER)
+                .compare_with(reverse)
+                .open(rustpath)
+                .unwrap();
+            let first = db.range(..).next().unwrap().unwrap().0;
+            assert_eq!(first, b"0999");
+        }
+        assert_eq!(Rc::strong_count(&calls), 1);
+        assert!(::std::fs::remove_file(rustpath).is_ok());
+    }
 }
@@ -0,0 +1,390 @@
+//
+// Copyright 2016 Ewan Higgs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use libc::{c_char, c_int, c_void};
+use std::cmp;
+use std::path::{Path, PathBuf};
+use std::slice;
+
+use tcutil;
+use tcutil::{ErrorCode, Result, TcBytes, TcError, path_to_cstring, tcfree};
+
+/// TCFDB - The fixed-length database instance.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct TCFDB(pub *const c_void);
+
+bitflags! {
+    flags AdditionalFlags: c_int {
+        const FDBFOPEN  = 1 << 0, // whether opened
+        const FDBFFATAL = 1 << 1  // whether with fatal error
+    }
+}
+
+bitflags! {
+    flags OpenModes : c_int {
+        const FDBOREADER = 1 << 0, // open as a reader
+        const FDBOWRITER = 1 << 1, // open as a writer
+        const FDBOCREAT  = 1 << 2, // writer creating
+        const FDBOTRUNC  = 1 << 3, // writer truncating
+        const FDBONOLCK  = 1 << 4, // open without locking
+        const FDBOLCKNB  = 1 << 5, // lock without blocking
+        const FDBOTSYNC  = 1 << 6  // synchronize every transaction
+    }
+}
+
+// Special IDs accepted in place of a record ID.
+pub const FDBIDMIN: i64  = -1; // minimum number
+pub const FDBIDPREV: i64 = -2; // less by one than the minimum
+pub const FDBIDMAX: i64  = -3; // maximum number
+pub const FDBIDNEXT: i64 = -4; // greater by one than the maximum
+
+/*
+ * In the following, I use *const u8 for void* since void* has no size, so,
+ * afaik, using c_void would just force further casts elsewhere.
+ */
+#[allow(dead_code)]
+#[link(name = "tokyocabinet")]
+extern {
+    // API of fixed-length database
+    pub fn tcfdberrmsg(ecode: c_int) -> *const c_char;
+    pub fn tcfdbnew() -> TCFDB;
+    pub fn tcfdbdel(fdb: TCFDB);
+    pub fn tcfdbecode(fdb: TCFDB) -> c_int;
+    pub fn tcfdbsetmutex(fdb: TCFDB) -> bool;
+    pub fn tcfdbtune(fdb: TCFDB, width: i32, limsiz: i64) -> bool;
+    pub fn tcfdbopen(fdb: TCFDB, path: *const c_char, omode: c_int) -> bool;
+    pub fn tcfdbclose(fdb: TCFDB) -> bool;
+    pub fn tcfdbput(fdb: TCFDB, id: i64, vbuf: *const u8, vsiz: c_int) -> bool;
+    pub fn tcfdbputkeep(fdb: TCFDB, id: i64, vbuf: *const u8, vsiz: c_int) -> bool;
+    pub fn tcfdbout(fdb: TCFDB, id: i64) -> bool;
+    pub fn tcfdbget(fdb: TCFDB, id: i64, sp: *mut c_int) -> *mut u8;
+    pub fn tcfdbiterinit(fdb: TCFDB) -> bool;
+    pub fn tcfdbiternext(fdb: TCFDB) -> u64;
+    pub fn tcfdbrange(fdb: TCFDB, lower: i64, upper: i64, max: c_int, np: *mut c_int) -> *mut u64;
+    pub fn tcfdbaddint(fdb: TCFDB, id: i64, num: c_int) -> c_int;
+    pub fn tcfdbsync(fdb: TCFDB) -> bool;
+    pub fn tcfdboptimize(fdb: TCFDB, width: i32, limsiz: i64) -> bool;
+    pub fn tcfdbvanish(fdb: TCFDB) -> bool;
+    pub fn tcfdbmin(fdb: TCFDB) -> u64;
+    pub fn tcfdbmax(fdb: TCFDB) -> u64;
+    pub fn tcfdbrnum(fdb: TCFDB) -> u64;
+}
+
+/// FixedTuning - Record width and file size limit of a fixed-length database.
+///
+/// Parameters left unset keep the Tokyo Cabinet defaults (255 byte records,
+/// a 256MiB file).
+#[derive(Clone, Debug)]
+pub struct FixedTuning {
+    width: Option<i32>,
+    limsiz: Option<i64>,
+}
+
+impl FixedTuning {
+    /// Tuning with every parameter left at its default.
+    pub fn new() -> FixedTuning {
+        FixedTuning { width: None, limsiz: None }
+    }
+
+    /// Width of the value of each record; longer values are truncated.
+    pub fn width(mut self, width: i32) -> FixedTuning {
+        self.width = Some(width);
+        self
+    }
+
+    /// Limit on the size of the database file.
+    pub fn limsiz(mut self, limsiz: i64) -> FixedTuning {
+        self.limsiz = Some(limsiz);
+        self
+    }
+}
+
+impl Default for FixedTuning {
+    fn default() -> FixedTuning {
+        FixedTuning::new()
+    }
+}
+
+/// FixedDb - An owned fixed-length database, keyed by record IDs.
+///
+/// Valid IDs run from 1 to the limit implied by the tuning. The underlying
+/// `TCFDB` is closed and deleted when the `FixedDb` is dropped.
+pub struct FixedDb {
+    db: TCFDB,
+    path: PathBuf,
+}
+
+impl FixedDb {
+    /// Open the database file at `path`.
+    pub fn open<P: AsRef<Path>>(path: P, modes: OpenModes) -> Result<FixedDb> {
+        FixedDb::open_with(path, modes, &FixedTuning::new())
+    }
+
+    /// Open the database file at `path`, tuning it first.
+    ///
+    /// Tuning only affects a database file created by this call.
+    pub fn open_with<P: AsRef<Path>>(path: P, modes: OpenModes, tuning: &FixedTuning) -> Result<FixedDb> {
+        let path = path.as_ref();
+        let cpath = path_to_cstring(path, "tcfdbopen")?;
+        let db = FixedDb { db: unsafe { tcfdbnew() }, path: path.to_path_buf() };
+        if tuning.width.is_some() || tuning.limsiz.is_some() {
+            let ok = unsafe { tcfdbtune(db.db, tuning.width.unwrap_or(-1), tuning.limsiz.unwrap_or(-1)) };
+            db.check(ok, "tcfdbtune")?;
+        }
+        if unsafe { tcfdbopen(db.db, cpath.as_ptr(), modes.bits()) } {
+            Ok(db)
+        } else {
+            Err(db.last_error("tcfdbopen"))
+        }
+    }
+
+    /// Store a record, overwriting any existing value.
+    pub fn put(&mut self, id: u64, value: &[u8]) -> Result<()> {
+        let id = self.raw_id(id, "tcfdbput")?;
+        let ok = unsafe { tcfdbput(self.db, id, value.as_ptr(), value.len() as c_int) };
+        self.check(ok, "tcfdbput")
+    }
+
+    /// Store a record unless the ID already exists.
+    ///
+    /// Returns `false` if an existing record was kept.
+    pub fn put_keep(&mut self, id: u64, value: &[u8]) -> Result<bool> {
+        let id = self.raw_id(id, "tcfdbputkeep")?;
+        let ok = unsafe { tcfdbputkeep(self.db, id, value.as_ptr(), value.len() as c_int) };
+        self.check_found(ok, "tcfdbputkeep", ErrorCode::Keep)
+    }
+
+    /// Store a record under the ID one past the current maximum, returning that ID.
+    pub fn append(&mut self, value: &[u8]) -> Result<u64> {
+        let ok = unsafe { tcfdbput(self.db, FDBIDNEXT, value.as_ptr(), value.len() as c_int) };
+        self.check(ok, "tcfdbput")?;
+        Ok(unsafe { tcfdbmax(self.db) })
+    }
+
+    /// Retrieve the value of a record.
+    pub fn get(&self, id: u64) -> Result<Option<TcBytes>> {
+        let id = self.raw_id(id, "tcfdbget")?;
+        let mut size: c_int = 0;
+        let vbuf = unsafe { tcfdbget(self.db, id, &mut size) };
+        if vbuf.is_null() {
+            return self.check_found(false, "tcfdbget", ErrorCode::NoRec).map(|_| None);
+        }
+        Ok(Some(unsafe { TcBytes::from_raw(vbuf, size as usize) }))
+    }
+
+    /// Remove a record.
+    ///
+    /// Returns `false` if there was no such record.
+    pub fn remove(&mut self, id: u64) -> Result<bool> {
+        let id = self.raw_id(id, "tcfdbout")?;
+        let ok = unsafe { tcfdbout(self.db, id) };
+        self.check_found(ok, "tcfdbout", ErrorCode::NoRec)
+    }
+
+    /// Add `num` to the integer stored in a record, creating it if missing.
+    ///
+    /// Returns the sum. The record must have been stored by this method, as
+    /// the value is kept as a native `int`.
+    pub fn add_int(&mut self, id: u64, num: i32) -> Result<i32> {
+        let id = self.raw_id(id, "tcfdbaddint")?;
+        let sum = unsafe { tcfdbaddint(self.db, id, num) };
+        if sum == c_int::MIN {
+            return Err(self.last_error("tcfdbaddint"));
+        }
+        Ok(sum)
+    }
+
+    /// The IDs of the records between `lower` and `upper` inclusive, in
+    /// ascending order, at most `max` of them if given.
+    pub fn range(&self, lower: u64, upper: u64, max: Option<usize>) -> Result<Vec<u64>> {
+        let lower = self.raw_id(lower, "tcfdbrange")?;
+        let upper = self.raw_id(upper, "tcfdbrange")?;
+        let max = max.map_or(-1, |m| cmp::min(m, c_int::MAX as usize) as c_int);
+        let mut num: c_int = 0;
+        unsafe {
+            let ids = tcfdbrange(self.db, lower, upper, max, &mut num);
+            if ids.is_null() {
+                return Err(self.last_error("tcfdbrange"));
+            }
+            let result = slice::from_raw_parts(ids, num as usize).to_vec();
+            tcfree(ids as *const c_void);
+            Ok(result)
+        }
+    }
+
+    /// Iterate over the IDs of every record, in ascending order.
+    pub fn ids(&mut self) -> Result<Ids<'_>> {
+        let ok = unsafe { tcfdbiterinit(self.db) };
+        self.check(ok, "tcfdbiterinit")?;
+        Ok(Ids { db: self, done: false })
+    }
+
+    /// Synchronize updated contents with the file and the device.
+    pub fn sync(&mut self) -> Result<()> {
+        let ok = unsafe { tcfdbsync(self.db) };
+        self.check(ok, "tcfdbsync")
+    }
+
+    /// Rebuild the database file, applying any parameters set in `tuning`.
+    pub fn optimize(&mut self, tuning: &FixedTuning) -> Result<()> {
+        let ok = unsafe { tcfdboptimize(self.db, tuning.width.unwrap_or(0), tuning.limsiz.unwrap_or(0)) };
+        self.check(ok, "tcfdboptimize")
+    }
+
+    /// Remove all records.
+    pub fn vanish(&mut self) -> Result<()> {
+        let ok = unsafe { tcfdbvanish(self.db) };
+        self.check(ok, "tcfdbvanish")
+    }
+
+    /// Close the database, reporting any error raised while doing so.
+    pub fn close(self) -> Result<()> {
+        let ok = unsafe { tcfdbclose(self.db) };
+        self.check(ok, "tcfdbclose")
+    }
+
+    /// The raw handle, for calling functions not wrapped here.
+    pub fn as_raw(&self) -> TCFDB {
+        self.db
+    }
+
+    // IDs past `i64::MAX` would wrap into the special negative IDs.
+    fn raw_id(&self, id: u64, op: &'static str) -> Result<i64> {
+        if id == 0 || id > i64::MAX as u64 {
+            return Err(TcError::with_errmsg(ErrorCode::Invalid.to_raw(), tcfdberrmsg, op, Some(&self.path)));
+        }
+        Ok(id as i64)
+    }
+
+    fn last_error(&self, op: &'static str) -> TcError {
+        tcutil::last_error(tcfdbecode, tcfdberrmsg, self.db, &self.path, op)
+    }
+
+    fn check(&self, ok: bool, op: &'static str) -> Result<()> {
+        tcutil::check(tcfdbecode, tcfdberrmsg, self.db, &self.path, ok, op)
+    }
+
+    fn check_found(&self, ok: bool, op: &'static str, benign: ErrorCode) -> Result<bool> {
+        tcutil::check_found(tcfdbecode, tcfdberrmsg, self.db, &self.path, ok, op, benign)
+    }
+}
+
+impl Drop for FixedDb {
+    fn drop(&mut self) {
+        unsafe { tcfdbdel(self.db) };
+    }
+}
+
+/// Ids - The record IDs of a `FixedDb`, in ascending order.
+///
+/// `tcfdbiternext` moves a position kept inside the `FixedDb`; starting another
+/// walk would reset it, hence the exclusive borrow.
+pub struct Ids<'a> {
+    db: &'a mut FixedDb,
+    done: bool,
+}
+
+impl<'a> Iterator for Ids<'a> {
+    type Item = Result<u64>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match unsafe { tcfdbiternext(self.db.db) } {
+            0 => {
+                self.done = true;
+                match self.db.last_error("tcfdbiternext") {
+                    ref e if e.code() == ErrorCode::NoRec => None,
+                    e => Some(Err(e)),
+                }
+            }
+            id => Some(Ok(id)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tcfdb::*;
+    use tcutil::*;
+
+    #[test]
+    fn test_new_del() {
+        unsafe {
+            let db = tcfdbnew();
+            assert!(!db.0.is_null());
+            tcfdbdel(db);
+        }
+    }
+
+    #[test]
+    fn test_fixeddb_basics() {
+        let rustpath = ".tcfdb_test_fixeddb_basics.tcf";
+        {
+            let mut db = FixedDb::open(rustpath, FDBOWRITER | FDBOCREAT | FDBOTRUNC).unwrap();
+            db.put(1, b"one").unwrap();
+            assert_eq!(db.get(1).unwrap().unwrap(), &b"one"[..]);
+            assert!(db.get(2).unwrap().is_none());
+            assert!(!db.put_keep(1, b"uno").unwrap());
+            assert!(db.put_keep(2, b"two").unwrap());
+            assert_eq!(db.append(b"three").unwrap(), 3);
+            assert!(db.remove(1).unwrap());
+            assert!(!db.remove(1).unwrap());
+            assert_eq!(db.get(0).err().map(|e| e.code()), Some(ErrorCode::Invalid));
+            assert_eq!(db.put(u64::MAX, b"").err().map(|e| e.code()), Some(ErrorCode::Invalid));
+            db.sync().unwrap();
+            db.vanish().unwrap();
+            assert!(db.get(2).unwrap().is_none());
+            db.close().unwrap();
+        }
+        assert!(::std::fs::remove_file(rustpath).is_ok());
+    }
+
+    #[test]
+    fn test_fixeddb_range_and_ids() {
+        let rustpath = ".tcfdb_test_fixeddb_range_and_ids.tcf";
+        {
+            let tuning = FixedTuning::new().width(8).limsiz(1 << 20);
+            let mut db = FixedDb::open_with(rustpath, FDBOWRITER | FDBOCREAT | FDBOTRUNC, &tuning).unwrap();
+            for id in &[2, 4, 6, 8] {
+                db.put(*id, b"a value longer than the width").unwrap();
+            }
+            assert_eq!(db.get(2).unwrap().unwrap(), &b"a value "[..]);
+            assert_eq!(db.range(3, 8, None).unwrap(), vec![4, 6, 8]);
+            assert_eq!(db.range(1, 8, Some(2)).unwrap(), vec![2, 4]);
+            let ids = db.ids().unwrap().collect::<Result<Vec<_>>>().unwrap();
+            assert_eq!(ids, vec![2, 4, 6, 8]);
+            db.optimize(&FixedTuning::new().width(16)).unwrap();
+            assert_eq!(db.get(8).unwrap().unwrap(), &b"a value "[..]);
+        }
+        assert!(::std::fs::remove_file(rustpath).is_ok());
+    }
+
+    #[test]
+    fn test_fixeddb_add_int() {
+        let rustpath = ".tcfdb_test_fixeddb_add_int.tcf";
+        {
+            let mut db = FixedDb::open(rustpath, FDBOWRITER | FDBOCREAT | FDBOTRUNC).unwrap();
+            assert_eq!(db.add_int(5, 3).unwrap(), 3);
+            assert_eq!(db.add_int(5, -1).unwrap(), 2);
+            db.put(6, b"x").unwrap();
+            assert_eq!(db.add_int(6, 1).err().map(|e| e.code()), Some(ErrorCode::Keep));
+        }
+        assert!(::std::fs::remove_file(rustpath).is_ok());
+    }
+}
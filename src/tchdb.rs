@@ -15,8 +15,12 @@
 //
 
 use libc::{c_char, c_int, c_long, c_void};
+use std::cmp;
+use std::path::{Path, PathBuf};
 
-use tcutil::TCCMP;
+use tcutil;
+use tcutil::{ErrorCode, Result, TCLIST, TCXSTR, TcBytes, TcError, list_to_vec, path_to_cstring,
+             tclistdel, tcxstrdel, tcxstrnew, xstr_to_vec};
 
 /// TCHDB - The hash table database instance.
 #[derive(Copy, Clone)]
@@ -79,11 +83,8 @@ extern {
     pub fn tchdbdel(hdb: TCHDB);
     pub fn tchdbecode(hdb: TCHDB)-> c_int;
     pub fn tchdbsetmutex(hdb: TCHDB) -> bool;
-    pub fn tchdbsetcmpfunc(hdb: TCHDB, cmp: TCCMP, cmpop: *const u8);
-    // TODO: check types since these are defined pretty specifically...
-    pub fn tchdbtune(hdb: TCHDB, lmemb: c_int, nmemb: c_int,
-                   bnum: c_long, bnum: c_char, fpow: c_char, opts: c_char) -> bool;
-    pub fn tchdbsetcache(hdb: TCHDB, lcnum: c_int, ncnum: c_int) -> bool;
+    pub fn tchdbtune(hdb: TCHDB, bnum: i64, apow: i8, fpow: i8, opts: u8) -> bool;
+    pub fn tchdbsetcache(hdb: TCHDB, rcnum: c_int) -> bool;
     pub fn tchdbsetxmsiz(hdb: TCHDB, xmsiz: c_long) -> bool; // 64bit
     pub fn tchdbsetdfunit(hdb: TCHDB, dfunit: c_int) -> bool; // 32bit 
     pub fn tchdbopen(hdb: TCHDB, path: *const c_char, omode: c_int) -> bool;
@@ -91,9 +92,338 @@ extern {
     pub fn tchdbput(hdb: TCHDB, kbuf: *const u8, ksiz: c_int, vbuf: *const u8, vsiz: c_int) -> bool;
     pub fn tchdbputkeep(hdb: TCHDB, kbuf: *const u8, ksiz: c_int, vbuf: *const u8, vsiz: c_int) -> bool;
     pub fn tchdbout(hdb: TCHDB, kbuf: *const u8, ksiz: c_int) -> bool;
-    pub fn tchdbget(hdb: TCHDB, kbuf: *const u8, ksiz: c_int, sp: *mut *const c_int) -> *mut u8;
+    pub fn tchdbget(hdb: TCHDB, kbuf: *const u8, ksiz: c_int, sp: *mut c_int) -> *mut u8;
     pub fn tchdbsync(hdb: TCHDB) -> bool;
     pub fn tchdbvanish(hdb: TCHDB) -> bool;
+    pub fn tchdbfwmkeys(hdb: TCHDB, pbuf: *const u8, psiz: c_int, max: c_int) -> TCLIST;
+    pub fn tchdbiterinit(hdb: TCHDB) -> bool;
+    pub fn tchdbiterinit2(hdb: TCHDB, kbuf: *const u8, ksiz: c_int) -> bool;
+    pub fn tchdbiternext(hdb: TCHDB, sp: *mut c_int) -> *mut u8;
+    pub fn tchdbiternext3(hdb: TCHDB, kxstr: TCXSTR, vxstr: TCXSTR) -> bool;
+}
+
+/// HashTuning - Tuning applied to a hash database before it is opened.
+///
+/// Parameters left unset keep the Tokyo Cabinet defaults.
+#[derive(Clone, Debug)]
+pub struct HashTuning {
+    bnum: Option<i64>,
+    apow: Option<i8>,
+    fpow: Option<i8>,
+    opts: Option<TuningOptions>,
+    rcnum: Option<i32>,
+    xmsiz: Option<i64>,
+    dfunit: Option<i32>,
+}
+
+impl HashTuning {
+    /// Tuning with every parameter left at its default.
+    pub fn new() -> HashTuning {
+        HashTuning {
+            bnum: None,
+            apow: None,
+            fpow: None,
+            opts: None,
+            rcnum: None,
+            xmsiz: None,
+            dfunit: None,
+        }
+    }
+
+    /// Number of elements of the bucket array.
+    pub fn bnum(mut self, bnum: i64) -> HashTuning {
+        self.bnum = Some(bnum);
+        self
+    }
+
+    /// Size of record alignment by power of 2.
+    pub fn apow(mut self, apow: i8) -> HashTuning {
+        self.apow = Some(apow);
+        self
+    }
+
+    /// Maximum number of elements of the free block pool by power of 2.
+    pub fn fpow(mut self, fpow: i8) -> HashTuning {
+        self.fpow = Some(fpow);
+        self
+    }
+
+    /// Tuning options.
+    pub fn opts(mut self, opts: TuningOptions) -> HashTuning {
+        self.opts = Some(opts);
+        self
+    }
+
+    /// Maximum number of records to be cached.
+    pub fn rcnum(mut self, rcnum: i32) -> HashTuning {
+        self.rcnum = Some(rcnum);
+        self
+    }
+
+    /// Size of the extra mapped memory.
+    pub fn xmsiz(mut self, xmsiz: i64) -> HashTuning {
+        self.xmsiz = Some(xmsiz);
+        self
+    }
+
+    /// Unit step number of auto defragmentation.
+    pub fn dfunit(mut self, dfunit: i32) -> HashTuning {
+        self.dfunit = Some(dfunit);
+        self
+    }
+
+    fn apply(&self, db: &HashDb) -> Result<()> {
+        if self.bnum.is_some() || self.apow.is_some() || self.fpow.is_some() || self.opts.is_some() {
+            let opts = self.opts.map_or(0, |o| o.bits() as u8);
+            let ok = unsafe {
+                tchdbtune(db.db, self.bnum.unwrap_or(-1), self.apow.unwrap_or(-1),
+                          self.fpow.unwrap_or(-1), opts)
+            };
+            db.check(ok, "tchdbtune")?;
+        }
+        if let Some(rcnum) = self.rcnum {
+            db.check(unsafe { tchdbsetcache(db.db, rcnum) }, "tchdbsetcache")?;
+        }
+        if let Some(xmsiz) = self.xmsiz {
+            db.check(unsafe { tchdbsetxmsiz(db.db, xmsiz as c_long) }, "tchdbsetxmsiz")?;
+        }
+        if let Some(dfunit) = self.dfunit {
+            db.check(unsafe { tchdbsetdfunit(db.db, dfunit) }, "tchdbsetdfunit")?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for HashTuning {
+    fn default() -> HashTuning {
+        HashTuning::new()
+    }
+}
+
+/// HashDb - An owned hash table database.
+///
+/// The underlying `TCHDB` is closed and deleted when the `HashDb` is dropped.
+pub struct HashDb {
+    db: TCHDB,
+    path: PathBuf,
+}
+
+impl HashDb {
+    /// Open the database file at `path`.
+    pub fn open<P: AsRef<Path>>(path: P, modes: OpenModes) -> Result<HashDb> {
+        HashDb::open_with(path, modes, &HashTuning::new())
+    }
+
+    /// Open the database file at `path`, tuning it first.
+    ///
+    /// Tuning only affects a database file created by this call.
+    pub fn open_with<P: AsRef<Path>>(path: P, modes: OpenModes, tuning: &HashTuning) -> Result<HashDb> {
+        let path = path.as_ref();
+        let cpath = path_to_cstring(path, "tchdbopen")?;
+        let db = HashDb { db: unsafe { tchdbnew() }, path: path.to_path_buf() };
+        tuning.apply(&db)?;
+        if unsafe { tchdbopen(db.db, cpath.as_ptr(), modes.bits()) } {
+            Ok(db)
+        } else {
+            Err(db.last_error("tchdbopen"))
+        }
+    }
+
+    /// Store a record, overwriting any existing value.
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let ok = unsafe {
+            tchdbput(self.db, key.as_ptr(), key.len() as c_int, value.as_ptr(), value.len() as c_int)
+        };
+        self.check(ok, "tchdbput")
+    }
+
+    /// Store a record unless the key already exists.
+    ///
+    /// Returns `false` if an existing record was kept.
+    pub fn put_keep(&mut self, key: &[u8], value: &[u8]) -> Result<bool> {
+        let ok = unsafe {
+            tchdbputkeep(self.db, key.as_ptr(), key.len() as c_int, value.as_ptr(), value.len() as c_int)
+        };
+        self.check_found(ok, "tchdbputkeep", ErrorCode::Keep)
+    }
+
+    /// Retrieve the value of a record.
+    pub fn get(&self, key: &[u8]) -> Result<Option<TcBytes>> {
+        let mut size: c_int = 0;
+        let vbuf = unsafe { tchdbget(self.db, key.as_ptr(), key.len() as c_int, &mut size) };
+        if vbuf.is_null() {
+            return self.check_found(false, "tchdbget", ErrorCode::NoRec).map(|_| None);
+        }
+        Ok(Some(unsafe { TcBytes::from_raw(vbuf, size as usize) }))
+    }
+
+    /// Remove a record.
+    ///
+    /// Returns `false` if there was no such record.
+    pub fn remove(&mut self, key: &[u8]) -> Result<bool> {
+        let ok = unsafe { tchdbout(self.db, key.as_ptr(), key.len() as c_int) };
+        self.check_found(ok, "tchdbout", ErrorCode::NoRec)
+    }
+
+    /// The keys beginning with `prefix`, at most `max` of them if given.
+    ///
+    /// Every key of a hash database is scanned, so this is slow on large databases.
+    pub fn keys_with_prefix(&self, prefix: &[u8], max: Option<usize>) -> Result<Vec<Vec<u8>>> {
+        let max = max.map_or(-1, |m| cmp::min(m, c_int::MAX as usize) as c_int);
+        unsafe {
+            let keys = tchdbfwmkeys(self.db, prefix.as_ptr(), prefix.len() as c_int, max);
+            let result = list_to_vec(keys);
+            tclistdel(keys);
+            Ok(result)
+        }
+    }
+
+    /// Synchronize updated contents with the file and the device.
+    pub fn sync(&mut self) -> Result<()> {
+        let ok = unsafe { tchdbsync(self.db) };
+        self.check(ok, "tchdbsync")
+    }
+
+    /// Remove all records.
+    pub fn vanish(&mut self) -> Result<()> {
+        let ok = unsafe { tchdbvanish(self.db) };
+        self.check(ok, "tchdbvanish")
+    }
+
+    /// Iterate over every record.
+    pub fn iter(&mut self) -> Result<Iter<'_>> {
+        let ok = unsafe { tchdbiterinit(self.db) };
+        self.check(ok, "tchdbiterinit")?;
+        Ok(Iter::new(self))
+    }
+
+    /// Iterate over the records, starting from the record of `key`.
+    pub fn iter_from(&mut self, key: &[u8]) -> Result<Iter<'_>> {
+        let ok = unsafe { tchdbiterinit2(self.db, key.as_ptr(), key.len() as c_int) };
+        self.check(ok, "tchdbiterinit2")?;
+        Ok(Iter::new(self))
+    }
+
+    /// Iterate over every key.
+    pub fn keys(&mut self) -> Result<Keys<'_>> {
+        let ok = unsafe { tchdbiterinit(self.db) };
+        self.check(ok, "tchdbiterinit")?;
+        Ok(Keys { db: self, done: false })
+    }
+
+    /// Iterate over the keys, starting from `key`.
+    pub fn keys_from(&mut self, key: &[u8]) -> Result<Keys<'_>> {
+        let ok = unsafe { tchdbiterinit2(self.db, key.as_ptr(), key.len() as c_int) };
+        self.check(ok, "tchdbiterinit2")?;
+        Ok(Keys { db: self, done: false })
+    }
+
+    /// Close the database, reporting any error raised while doing so.
+    pub fn close(self) -> Result<()> {
+        let ok = unsafe { tchdbclose(self.db) };
+        self.check(ok, "tchdbclose")
+    }
+
+    /// The raw handle, for calling functions not wrapped here.
+    pub fn as_raw(&self) -> TCHDB {
+        self.db
+    }
+
+    fn last_error(&self, op: &'static str) -> TcError {
+        tcutil::last_error(tchdbecode, tchdberrmsg, self.db, &self.path, op)
+    }
+
+    fn check(&self, ok: bool, op: &'static str) -> Result<()> {
+        tcutil::check(tchdbecode, tchdberrmsg, self.db, &self.path, ok, op)
+    }
+
+    fn check_found(&self, ok: bool, op: &'static str, benign: ErrorCode) -> Result<bool> {
+        tcutil::check_found(tchdbecode, tchdberrmsg, self.db, &self.path, ok, op, benign)
+    }
+}
+
+impl Drop for HashDb {
+    fn drop(&mut self) {
+        unsafe { tchdbdel(self.db) };
+    }
+}
+
+// The iterator ends cleanly when `tchdbiternext*` fails with `TCENOREC`.
+fn next_or_end<T>(db: &HashDb, op: &'static str) -> Option<Result<T>> {
+    match db.last_error(op) {
+        ref e if e.code() == ErrorCode::NoRec => None,
+        e => Some(Err(e)),
+    }
+}
+
+/// Iter - The records of a `HashDb`, in storage order.
+///
+/// Walks with the database's own iterator, which is why it holds the
+/// `HashDb` mutably until dropped.
+pub struct Iter<'a> {
+    db: &'a mut HashDb,
+    kxstr: TCXSTR,
+    vxstr: TCXSTR,
+    done: bool,
+}
+
+impl<'a> Iter<'a> {
+    fn new(db: &'a mut HashDb) -> Iter<'a> {
+        unsafe {
+            Iter { db, kxstr: tcxstrnew(), vxstr: tcxstrnew(), done: false }
+        }
+    }
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        if unsafe { tchdbiternext3(self.db.db, self.kxstr, self.vxstr) } {
+            unsafe { Some(Ok((xstr_to_vec(self.kxstr), xstr_to_vec(self.vxstr)))) }
+        } else {
+            self.done = true;
+            next_or_end(self.db, "tchdbiternext3")
+        }
+    }
+}
+
+impl<'a> Drop for Iter<'a> {
+    fn drop(&mut self) {
+        unsafe {
+            tcxstrdel(self.kxstr);
+            tcxstrdel(self.vxstr);
+        }
+    }
+}
+
+/// Keys - The keys of a `HashDb`, in storage order.
+///
+/// Uses the same iterator as `Iter`, so only one of the two can be live.
+pub struct Keys<'a> {
+    db: &'a mut HashDb,
+    done: bool,
+}
+
+impl<'a> Iterator for Keys<'a> {
+    type Item = Result<TcBytes>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let mut size: c_int = 0;
+        let kbuf = unsafe { tchdbiternext(self.db.db, &mut size) };
+        if kbuf.is_null() {
+            self.done = true;
+            next_or_end(self.db, "tchdbiternext")
+        } else {
+            Some(Ok(unsafe { TcBytes::from_raw(kbuf, size as usize) }))
+        }
+    }
 }
 
 #[cfg(test)]
@@ -101,6 +431,7 @@ mod test {
     use libc::{c_int, c_void};
     use tchdb::*;
     use tcutil::*;
+    use std::path::Path;
     use std::slice;
     use std::ffi::{CStr, CString};
 
@@ -142,9 +473,8 @@ mod test {
             let k = b"hello";
             let v = b"world";
             assert!(tchdbput(db, k.clone().as_ptr(), k.len() as c_int, v.clone().as_ptr(), v.len() as c_int));
-            let mut v2_sz: *const i32 = 0 as *const i32;
-            let v2_sz_ptr: *mut *const i32 = &mut v2_sz;
-            let v2 = tchdbget(db, k.as_ptr(), k.len() as c_int, v2_sz_ptr);
+            let mut v2_sz: c_int = 0;
+            let v2 = tchdbget(db, k.as_ptr(), k.len() as c_int, &mut v2_sz);
             assert!(!v2.is_null());
             assert_eq!(v.len(), v2_sz as usize);
             let v2_slice = slice::from_raw_parts(v2, v2_sz as usize);
@@ -156,4 +486,94 @@ mod test {
             assert!(::std::fs::remove_file(rustpath).is_ok());
         }
     }
+
+    #[test]
+    fn test_hashdb_basics() {
+        let rustpath = ".tchdb_test_hashdb_basics.tch";
+        {
+            let mut db = HashDb::open(rustpath, HDBOWRITER | HDBOCREAT | HDBOTRUNC).unwrap();
+            db.put(b"hello", b"world").unwrap();
+            assert_eq!(db.get(b"hello").unwrap().unwrap(), &b"world"[..]);
+            assert!(db.get(b"missing").unwrap().is_none());
+            assert!(!db.put_keep(b"hello", b"there").unwrap());
+            assert!(db.put_keep(b"hi", b"there").unwrap());
+            assert!(db.remove(b"hello").unwrap());
+            assert!(!db.remove(b"hello").unwrap());
+            db.sync().unwrap();
+            db.vanish().unwrap();
+            assert!(db.get(b"hi").unwrap().is_none());
+            db.close().unwrap();
+        }
+        assert!(::std::fs::remove_file(rustpath).is_ok());
+    }
+
+    #[test]
+    fn test_hashdb_open_missing_file() {
+        let err = HashDb::open(".tchdb_test_hashdb_missing.tch", HDBOREADER).err().unwrap();
+        assert_eq!(err.code(), ErrorCode::NoFile);
+        assert_eq!(err.op(), "tchdbopen");
+        assert_eq!(err.path(), Some(Path::new(".tchdb_test_hashdb_missing.tch")));
+    }
+
+    #[test]
+    fn test_hashdb_tuning() {
+        let rustpath = ".tchdb_test_hashdb_tuning.tch";
+        {
+            let tuning = HashTuning::new()
+                .bnum(1_000_003)
+                .apow(4)
+                .fpow(10)
+                .opts(HDBTLARGE | HDBTDEFLATE)
+                .rcnum(1024)
+                .xmsiz(1 << 20)
+                .dfunit(8);
+            let mut db = HashDb::open_with(rustpath, HDBOWRITER | HDBOCREAT | HDBOTRUNC, &tuning).unwrap();
+            db.put(b"hello", b"world").unwrap();
+            assert_eq!(db.get(b"hello").unwrap().unwrap(), &b"world"[..]);
+        }
+        assert!(::std::fs::metadata(rustpath).unwrap().len() > 1_000_003 * 4);
+        assert!(::std::fs::remove_file(rustpath).is_ok());
+    }
+
+    #[test]
+    fn test_hashdb_iter() {
+        let rustpath = ".tchdb_test_hashdb_iter.tch";
+        {
+            let mut db = HashDb::open(rustpath, HDBOWRITER | HDBOCREAT | HDBOTRUNC).unwrap();
+            for &(k, v) in &[(b"a", b"1"), (b"b", b"2"), (b"c", b"3")] {
+                db.put(k, v).unwrap();
+            }
+            let mut records = db.iter().unwrap().collect::<Result<Vec<_>>>().unwrap();
+            records.sort();
+            assert_eq!(records, vec![(b"a".to_vec(), b"1".to_vec()),
+                                     (b"b".to_vec(), b"2".to_vec()),
+                                     (b"c".to_vec(), b"3".to_vec())]);
+
+            let keys = db.keys().unwrap().collect::<Result<Vec<_>>>().unwrap();
+            assert_eq!(keys.len(), 3);
+            let rest = db.keys_from(&keys[1]).unwrap().collect::<Result<Vec<_>>>().unwrap();
+            assert_eq!(rest, &keys[1..]);
+            let (first, _) = db.iter_from(&keys[2]).unwrap().next().unwrap().unwrap();
+            assert_eq!(keys[2], first);
+            assert_eq!(db.iter_from(b"missing").err().map(|e| e.code()), Some(ErrorCode::NoRec));
+        }
+        assert!(::std::fs::remove_file(rustpath).is_ok());
+    }
+
+    #[test]
+    fn test_hashdb_keys_with_prefix() {
+        let rustpath = ".tchdb_test_hashdb_keys_with_prefix.tch";
+        {
+            let mut db = HashDb::open(rustpath, HDBOWRITER | HDBOCREAT | HDBOTRUNC).unwrap();
+            for k in &[&b"user:1"[..], b"user:2", b"group:1"] {
+                db.put(k, b"").unwrap();
+            }
+            let mut keys = db.keys_with_prefix(b"user:", None).unwrap();
+            keys.sort();
+            assert_eq!(keys, vec![b"user:1".to_vec(), b"user:2".to_vec()]);
+            assert_eq!(db.keys_with_prefix(b"user:", Some(1)).unwrap().len(), 1);
+            assert!(db.keys_with_prefix(b"none:", None).unwrap().is_empty());
+        }
+        assert!(::std::fs::remove_file(rustpath).is_ok());
+    }
 }
@@ -0,0 +1,536 @@
+//
+// Copyright 2016 Ewan Higgs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Serde support mapping flat structs to and from table database columns.
+//!
+//! Each field becomes a column named after it. Numbers are written as decimal
+//! strings so the `Num*` query operators work on them, booleans as `true` or
+//! `false`, unit enum variants as their names, and a `None` field leaves its
+//! column out. Nested structs, sequences and maps are rejected.
+
+use serde::de::{self, DeserializeOwned, IntoDeserializer, MapAccess, Unexpected, Visitor};
+use serde::forward_to_deserialize_any;
+use serde::ser::{self, Impossible, Serialize, SerializeMap, SerializeStruct};
+use serde::{Deserialize, Deserializer, Serializer};
+use std::collections::btree_map;
+use std::fmt::Display;
+use std::str;
+
+use tctdb::{Columns, TableDb};
+use tcutil::{ErrorCode, Result, TcError};
+
+impl ser::Error for TcError {
+    fn custom<T: Display>(msg: T) -> TcError {
+        TcError::with_message(ErrorCode::Invalid, "serialize", None, msg.to_string())
+    }
+}
+
+impl de::Error for TcError {
+    fn custom<T: Display>(msg: T) -> TcError {
+        TcError::with_message(ErrorCode::Invalid, "deserialize", None, msg.to_string())
+    }
+}
+
+/// The columns of a struct or map.
+pub fn to_columns<T: Serialize + ?Sized>(value: &T) -> Result<Columns> {
+    value.serialize(ColumnSerializer)
+}
+
+/// A struct or map read back from its columns.
+pub fn from_columns<'a, T: Deserialize<'a>>(cols: &'a Columns) -> Result<T> {
+    T::deserialize(ColumnDeserializer { cols })
+}
+
+impl TableDb {
+    /// Store `value` as the columns of a record, replacing any existing record.
+    pub fn put_struct<T: Serialize + ?Sized>(&mut self, pk: &[u8], value: &T) -> Result<()> {
+        let cols = to_columns(value).map_err(|e| self.at_path(e, "serialize"))?;
+        self.put(pk, &cols)
+    }
+
+    /// Read the columns of a record back into a `T`.
+    pub fn get_struct<T: DeserializeOwned>(&self, pk: &[u8]) -> Result<Option<T>> {
+        match self.get(pk)? {
+            Some(cols) => from_columns(&cols).map(Some).map_err(|e| self.at_path(e, "deserialize")),
+            None => Ok(None),
+        }
+    }
+
+    // Report a serde error against this database.
+    fn at_path(&self, e: TcError, op: &'static str) -> TcError {
+        TcError::with_message(e.code(), op, Some(&self.path), e.message().to_string())
+    }
+}
+
+fn unsupported(what: &str) -> TcError {
+    ser::Error::custom(format!("{} cannot be stored in a table column", what))
+}
+
+/// ColumnSerializer - Serializes a struct or map into `Columns`.
+pub struct ColumnSerializer;
+
+/// Collects the fields of a struct or the entries of a map.
+pub struct ColumnCollector {
+    cols: Columns,
+    key: Option<Vec<u8>>,
+}
+
+impl ColumnCollector {
+    fn insert<T: Serialize + ?Sized>(&mut self, key: Vec<u8>, value: &T) -> Result<()> {
+        if let Some(value) = value.serialize(ValueSerializer)? {
+            self.cols.insert(key, value);
+        }
+        Ok(())
+    }
+}
+
+macro_rules! reject {
+    ($($method:ident($($arg:ty),*) -> $ok:ty, $what:expr;)*) => {
+        $(
+            fn $method(self, $(_: $arg),*) -> Result<$ok> {
+                Err(unsupported($what))
+            }
+        )*
+    }
+}
+
+impl Serializer for ColumnSerializer {
+    type Ok = Columns;
+    type Error = TcError;
+    type SerializeSeq = Impossible<Columns, TcError>;
+    type SerializeTuple = Impossible<Columns, TcError>;
+    type SerializeTupleStruct = Impossible<Columns, TcError>;
+    type SerializeTupleVariant = Impossible<Columns, TcError>;
+    type SerializeMap = ColumnCollector;
+    type SerializeStruct = ColumnCollector;
+    type SerializeStructVariant = Impossible<Columns, TcError>;
+
+    reject! {
+        serialize_bool(bool) -> Columns, "a bare bool";
+        serialize_i8(i8) -> Columns, "a bare number";
+        serialize_i16(i16) -> Columns, "a bare number";
+        serialize_i32(i32) -> Columns, "a bare number";
+        serialize_i64(i64) -> Columns, "a bare number";
+        serialize_u8(u8) -> Columns, "a bare number";
+        serialize_u16(u16) -> Columns, "a bare number";
+        serialize_u32(u32) -> Columns, "a bare number";
+        serialize_u64(u64) -> Columns, "a bare number";
+        serialize_f32(f32) -> Columns, "a bare number";
+        serialize_f64(f64) -> Columns, "a bare number";
+        serialize_char(char) -> Columns, "a bare char";
+        serialize_str(&str) -> Columns, "a bare string";
+        serialize_bytes(&[u8]) -> Columns, "bare bytes";
+        serialize_none() -> Columns, "a bare option";
+        serialize_unit() -> Columns, "a bare unit";
+        serialize_unit_struct(&'static str) -> Columns, "a unit struct";
+        serialize_unit_variant(&'static str, u32, &'static str) -> Columns, "an enum";
+        serialize_seq(Option<usize>) -> Impossible<Columns, TcError>, "a sequence";
+        serialize_tuple(usize) -> Impossible<Columns, TcError>, "a tuple";
+        serialize_tuple_struct(&'static str, usize) -> Impossible<Columns, TcError>, "a tuple struct";
+        serialize_tuple_variant(&'static str, u32, &'static str, usize) -> Impossible<Columns, TcError>, "an enum";
+        serialize_struct_variant(&'static str, u32, &'static str, usize) -> Impossible<Columns, TcError>, "an enum";
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, _: &T) -> Result<Columns> {
+        Err(unsupported("a bare option"))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _: &'static str, value: &T) -> Result<Columns> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _: &'static str, _: u32, _: &'static str,
+                                                        _: &T) -> Result<Columns> {
+        Err(unsupported("an enum"))
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<ColumnCollector> {
+        Ok(ColumnCollector { cols: Columns::new(), key: None })
+    }
+
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<ColumnCollector> {
+        Ok(ColumnCollector { cols: Columns::new(), key: None })
+    }
+}
+
+impl SerializeStruct for ColumnCollector {
+    type Ok = Columns;
+    type Error = TcError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<()> {
+        self.insert(key.as_bytes().to_vec(), value)
+    }
+
+    fn end(self) -> Result<Columns> {
+        Ok(self.cols)
+    }
+}
+
+impl SerializeMap for ColumnCollector {
+    type Ok = Columns;
+    type Error = TcError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+        match key.serialize(ValueSerializer)? {
+            Some(key) => {
+                self.key = Some(key);
+                Ok(())
+            }
+            None => Err(unsupported("a missing column name")),
+        }
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        match self.key.take() {
+            Some(key) => self.insert(key, value),
+            None => Err(ser::Error::custom("map value serialized before its key")),
+        }
+    }
+
+    fn end(self) -> Result<Columns> {
+        Ok(self.cols)
+    }
+}
+
+// Serializes a single column value; `None` leaves the column out.
+struct ValueSerializer;
+
+macro_rules! to_text {
+    ($($method:ident($ty:ty);)*) => {
+        $(
+            fn $method(self, v: $ty) -> Result<Option<Vec<u8>>> {
+                Ok(Some(v.to_string().into_bytes()))
+            }
+        )*
+    }
+}
+
+impl Serializer for ValueSerializer {
+    type Ok = Option<Vec<u8>>;
+    type Error = TcError;
+    type SerializeSeq = Impossible<Option<Vec<u8>>, TcError>;
+    type SerializeTuple = Impossible<Option<Vec<u8>>, TcError>;
+    type SerializeTupleStruct = Impossible<Option<Vec<u8>>, TcError>;
+    type SerializeTupleVariant = Impossible<Option<Vec<u8>>, TcError>;
+    type SerializeMap = Impossible<Option<Vec<u8>>, TcError>;
+    type SerializeStruct = Impossible<Option<Vec<u8>>, TcError>;
+    type SerializeStructVariant = Impossible<Option<Vec<u8>>, TcError>;
+
+    to_text! {
+        serialize_bool(bool);
+        serialize_i8(i8);
+        serialize_i16(i16);
+        serialize_i32(i32);
+        serialize_i64(i64);
+        serialize_u8(u8);
+        serialize_u16(u16);
+        serialize_u32(u32);
+        serialize_u64(u64);
+        serialize_f32(f32);
+        serialize_f64(f64);
+        serialize_char(char);
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Option<Vec<u8>>> {
+        Ok(Some(v.as_bytes().to_vec()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(Some(v.to_vec()))
+    }
+
+    fn serialize_none(self) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Option<Vec<u8>>> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Option<Vec<u8>>> {
+        Ok(Some(Vec::new()))
+    }
+
+    fn serialize_unit_struct(self, _: &'static str) -> Result<Option<Vec<u8>>> {
+        Ok(Some(Vec::new()))
+    }
+
+    fn serialize_unit_variant(self, _: &'static str, _: u32, variant: &'static str) -> Result<Option<Vec<u8>>> {
+        Ok(Some(variant.as_bytes().to_vec()))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _: &'static str, value: &T) -> Result<Option<Vec<u8>>> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _: &'static str, _: u32, _: &'static str,
+                                                        _: &T) -> Result<Option<Vec<u8>>> {
+        Err(unsupported("an enum variant with data"))
+    }
+
+    reject! {
+        serialize_seq(Option<usize>) -> Impossible<Option<Vec<u8>>, TcError>, "a sequence";
+        serialize_tuple(usize) -> Impossible<Option<Vec<u8>>, TcError>, "a tuple";
+        serialize_tuple_struct(&'static str, usize) -> Impossible<Option<Vec<u8>>, TcError>, "a tuple struct";
+        serialize_tuple_variant(&'static str, u32, &'static str, usize) -> Impossible<Option<Vec<u8>>, TcError>,
+            "an enum variant with data";
+        serialize_map(Option<usize>) -> Impossible<Option<Vec<u8>>, TcError>, "a nested map";
+        serialize_struct(&'static str, usize) -> Impossible<Option<Vec<u8>>, TcError>, "a nested struct";
+        serialize_struct_variant(&'static str, u32, &'static str, usize) -> Impossible<Option<Vec<u8>>, TcError>,
+            "an enum variant with data";
+    }
+}
+
+/// ColumnDeserializer - Deserializes a struct or map from `Columns`.
+pub struct ColumnDeserializer<'a> {
+    cols: &'a Columns,
+}
+
+impl<'a> ColumnDeserializer<'a> {
+    /// A deserializer reading `cols`.
+    pub fn new(cols: &'a Columns) -> ColumnDeserializer<'a> {
+        ColumnDeserializer { cols }
+    }
+}
+
+impl<'de> Deserializer<'de> for ColumnDeserializer<'de> {
+    type Error = TcError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_map(ColumnAccess { iter: self.cols.iter(), value: None })
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+// Hands each column to a struct or map visitor in turn.
+struct ColumnAccess<'de> {
+    iter: btree_map::Iter<'de, Vec<u8>, Vec<u8>>,
+    value: Option<&'de [u8]>,
+}
+
+impl<'de> MapAccess<'de> for ColumnAccess<'de> {
+    type Error = TcError;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+        match self.iter.next() {
+            Some((key, value)) => {
+                self.value = Some(value);
+                seed.deserialize(ValueDeserializer(key)).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        match self.value.take() {
+            Some(value) => seed.deserialize(ValueDeserializer(value)),
+            None => Err(de::Error::custom("column value requested before its name")),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+// Deserializes a single column value.
+struct ValueDeserializer<'de>(&'de [u8]);
+
+impl<'de> ValueDeserializer<'de> {
+    fn text(&self) -> Result<&'de str> {
+        str::from_utf8(self.0).map_err(|_| de::Error::invalid_value(Unexpected::Bytes(self.0), &"UTF-8 text"))
+    }
+}
+
+macro_rules! parse_text {
+    ($($method:ident => $visit:ident;)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+                let text = self.text()?;
+                match text.trim().parse() {
+                    Ok(v) => visitor.$visit(v),
+                    Err(_) => Err(de::Error::invalid_value(Unexpected::Str(text), &visitor)),
+                }
+            }
+        )*
+    }
+}
+
+impl<'de> Deserializer<'de> for ValueDeserializer<'de> {
+    type Error = TcError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match str::from_utf8(self.0) {
+            Ok(text) => visitor.visit_borrowed_str(text),
+            Err(_) => visitor.visit_borrowed_bytes(self.0),
+        }
+    }
+
+    parse_text! {
+        deserialize_bool => visit_bool;
+        deserialize_i8 => visit_i8;
+        deserialize_i16 => visit_i16;
+        deserialize_i32 => visit_i32;
+        deserialize_i64 => visit_i64;
+        deserialize_u8 => visit_u8;
+        deserialize_u16 => visit_u16;
+        deserialize_u32 => visit_u32;
+        deserialize_u64 => visit_u64;
+        deserialize_f32 => visit_f32;
+        deserialize_f64 => visit_f64;
+        deserialize_char => visit_char;
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_borrowed_bytes(self.0)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_borrowed_bytes(self.0)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(self, _: &'static str, visitor: V) -> Result<V::Value> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _: &'static str, visitor: V) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, _: &'static str, _: &'static [&'static str],
+                                         visitor: V) -> Result<V::Value> {
+        let text = self.text()?;
+        visitor.visit_enum(text.into_deserializer())
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_unit()
+    }
+
+    forward_to_deserialize_any! {
+        i128 u128 str string seq tuple tuple_struct map struct identifier
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde::{Deserialize, Serialize};
+    use std::collections::BTreeMap;
+    use std::path::Path;
+    use tcserde::*;
+    use tctdb::*;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum Role {
+        Admin,
+        Member,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct User {
+        name: String,
+        age: u32,
+        score: f64,
+        active: bool,
+        role: Role,
+        nickname: Option<String>,
+    }
+
+    fn alice() -> User {
+        User {
+            name: "alice".to_string(),
+            age: 30,
+            score: -1.5,
+            active: true,
+            role: Role::Admin,
+            nickname: None,
+        }
+    }
+
+    #[test]
+    fn test_columns_round_trip() {
+        let cols = to_columns(&alice()).unwrap();
+        let mut expected = Columns::new();
+        for &(k, v) in &[("name", "alice"), ("age", "30"), ("score", "-1.5"), ("active", "true"), ("role", "Admin")] {
+            expected.insert(k.as_bytes().to_vec(), v.as_bytes().to_vec());
+        }
+        assert_eq!(cols, expected);
+        assert_eq!(from_columns::<User>(&cols).unwrap(), alice());
+
+        let mut map = BTreeMap::new();
+        map.insert("x", 1);
+        assert_eq!(from_columns::<BTreeMap<String, i64>>(&to_columns(&map).unwrap()).unwrap().get("x"), Some(&1));
+    }
+
+    #[test]
+    fn test_columns_errors() {
+        #[derive(Serialize)]
+        struct Nested {
+            tags: Vec<String>,
+        }
+        let err = to_columns(&Nested { tags: vec![] }).err().unwrap();
+        assert_eq!(err.code(), ErrorCode::Invalid);
+        assert_eq!(err.op(), "serialize");
+        assert!(to_columns(&42).is_err());
+
+        let mut cols = to_columns(&alice()).unwrap();
+        cols.insert(b"age".to_vec(), b"old".to_vec());
+        let err = from_columns::<User>(&cols).err().unwrap();
+        assert_eq!(err.op(), "deserialize");
+        cols.remove(&b"age"[..]);
+        assert!(from_columns::<User>(&cols).is_err());
+    }
+
+    #[test]
+    fn test_tabledb_structs() {
+        let rustpath = ".tcserde_test_tabledb_structs.tct";
+        {
+            let mut db = TableDb::open(rustpath, TDBOWRITER | TDBOCREAT | TDBOTRUNC).unwrap();
+            db.put_struct(b"1", &alice()).unwrap();
+            let bob = User { name: "bob".to_string(), age: 25, nickname: Some("bobby".to_string()), ..alice() };
+            db.put_struct(b"2", &bob).unwrap();
+            assert_eq!(db.get_struct::<User>(b"1").unwrap(), Some(alice()));
+            assert_eq!(db.get_struct::<User>(b"2").unwrap(), Some(bob));
+            assert_eq!(db.get_struct::<User>(b"3").unwrap(), None);
+            let pks = db.query().add_cond("age", QueryOp::NumLt, "28").search().unwrap();
+            assert_eq!(pks, vec![b"2".to_vec()]);
+
+            let err = db.put_struct(b"3", &42).err().unwrap();
+            assert_eq!(err.op(), "serialize");
+            assert_eq!(err.path(), Some(Path::new(rustpath)));
+            let mut cols = to_columns(&alice()).unwrap();
+            cols.insert(b"age".to_vec(), b"old".to_vec());
+            db.put(b"3", &cols).unwrap();
+            let err = db.get_struct::<User>(b"3").err().unwrap();
+            assert_eq!(err.op(), "deserialize");
+            assert_eq!(err.path(), Some(Path::new(rustpath)));
+        }
+        assert!(::std::fs::remove_file(rustpath).is_ok());
+    }
+}
@@ -0,0 +1,1814 @@
+//
+// Copyright 2016 Ewan Higgs
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use libc::{c_char, c_int, c_void};
+use std::cmp;
+use std::collections::BTreeMap;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::result;
+
+use tcutil;
+use tcutil::{Callback, ErrorCode, KwicOptions, Result, TCLIST, TCMAP, TcBytes, TcError, catch_panic, list_to_vec,
+             map_assign, map_from_btree, map_to_btree, path_to_cstring, raw_slice, tclistdel, tcmapdel};
+
+/// TCTDB - The table database instance.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct TCTDB(pub *const c_void);
+
+/// TDBQRY - A query on a table database.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct TDBQRY(pub *const c_void);
+
+bitflags! {
+    flags AdditionalFlags: c_int {
+        const TDBFOPEN  = 1 << 0, // whether opened
+        const TDBFFATAL = 1 << 1  // whether with fatal error
+    }
+}
+
+bitflags! {
+    flags TuningOptions : c_int {
+        const TDBTLARGE   = 1<<0, // use 64-bit bucket array
+        const TDBTDEFLATE = 1<<1, // compress each page with Deflate
+        const TDBTBZIP    = 1<<2, // compress each record with BZIP2
+        const TDBTTCBS    = 1<<3, // compress each page with TCBS
+        const TDBTEXCODEC = 1<<4  // compress each record with outer functions
+    }
+}
+
+bitflags! {
+    flags OpenModes : c_int {
+        const TDBOREADER = 1 << 0, // open as a reader
+        const TDBOWRITER = 1 << 1, // open as a writer
+        const TDBOCREAT  = 1 << 2, // writer creating
+        const TDBOTRUNC  = 1 << 3, // writer truncating
+        const TDBONOLCK  = 1 << 4, // open without locking
+        const TDBOLCKNB  = 1 << 5, // lock without blocking
+        const TDBOTSYNC  = 1 << 6  // synchronize every transaction
+    }
+}
+
+// Post-processing flags returned by a `TDBQRYPROC`.
+pub const TDBQPPUT: c_int  = 1 << 0;  // modifying the record
+pub const TDBQPOUT: c_int  = 1 << 1;  // removing the record
+pub const TDBQPSTOP: c_int = 1 << 24; // stopping the iteration
+
+// Set operations of a meta-search.
+pub const TDBMSUNION: c_int = 0; // union
+pub const TDBMSISECT: c_int = 1; // intersection
+pub const TDBMSDIFF: c_int  = 2; // difference
+
+// Index types.
+pub const TDBITLEXICAL: c_int = 0;       // lexical string
+pub const TDBITDECIMAL: c_int = 1;       // decimal string
+pub const TDBITTOKEN: c_int   = 2;       // token inverted index
+pub const TDBITQGRAM: c_int   = 3;       // q-gram inverted index
+pub const TDBITOPT: c_int     = 9998;    // optimize
+pub const TDBITVOID: c_int    = 9999;    // void
+pub const TDBITKEEP: c_int    = 1 << 24; // keep existing index
+
+// Query condition operators.
+pub const TDBQCSTREQ: c_int   = 0;  // string is equal to
+pub const TDBQCSTRINC: c_int  = 1;  // string is included in
+pub const TDBQCSTRBW: c_int   = 2;  // string begins with
+pub const TDBQCSTREW: c_int   = 3;  // string ends with
+pub const TDBQCSTRAND: c_int  = 4;  // string includes all tokens in
+pub const TDBQCSTROR: c_int   = 5;  // string includes at least one token in
+pub const TDBQCSTROREQ: c_int = 6;  // string is equal to at least one token in
+pub const TDBQCSTRRX: c_int   = 7;  // string matches regular expressions of
+pub const TDBQCNUMEQ: c_int   = 8;  // number is equal to
+pub const TDBQCNUMGT: c_int   = 9;  // number is greater than
+pub const TDBQCNUMGE: c_int   = 10; // number is greater than or equal to
+pub const TDBQCNUMLT: c_int   = 11; // number is less than
+pub const TDBQCNUMLE: c_int   = 12; // number is less than or equal to
+pub const TDBQCNUMBT: c_int   = 13; // number is between two tokens of
+pub const TDBQCNUMOREQ: c_int = 14; // number is equal to at least one token in
+pub const TDBQCFTSPH: c_int   = 15; // full-text search with the phrase of
+pub const TDBQCFTSAND: c_int  = 16; // full-text search with all tokens in
+pub const TDBQCFTSOR: c_int   = 17; // full-text search with at least one token in
+pub const TDBQCFTSEX: c_int   = 18; // full-text search with the compound expression of
+pub const TDBQCNEGATE: c_int  = 1 << 24; // negation flag
+pub const TDBQCNOIDX: c_int   = 1 << 25; // no index flag
+
+// Query order types.
+pub const TDBQOSTRASC: c_int  = 0; // string ascending
+pub const TDBQOSTRDESC: c_int = 1; // string descending
+pub const TDBQONUMASC: c_int  = 2; // number ascending
+pub const TDBQONUMDESC: c_int = 3; // number descending
+
+/// Visit a record found by a query, returning `TDBQP*` flags. The columns may
+/// be modified in place, to be stored if `TDBQPPUT` is returned.
+pub type TDBQRYPROC = unsafe extern "C" fn(pkbuf: *const u8, pksiz: c_int, cols: TCMAP, op: *const u8) -> c_int;
+
+/*
+ * In the following, I use *const u8 for void* since void* has no size, so,
+ * afaik, using c_void would just force further casts elsewhere.
+ */
+#[allow(dead_code)]
+#[link(name = "tokyocabinet")]
+extern {
+    // API of table database
+    pub fn tctdberrmsg(ecode: c_int) -> *const c_char;
+    pub fn tctdbnew() -> TCTDB;
+    pub fn tctdbdel(tdb: TCTDB);
+    pub fn tctdbecode(tdb: TCTDB) -> c_int;
+    pub fn tctdbsetmutex(tdb: TCTDB) -> bool;
+    pub fn tctdbtune(tdb: TCTDB, bnum: i64, apow: i8, fpow: i8, opts: u8) -> bool;
+    pub fn tctdbsetcache(tdb: TCTDB, rcnum: i32, lcnum: i32, ncnum: i32) -> bool;
+    pub fn tctdbsetxmsiz(tdb: TCTDB, xmsiz: i64) -> bool;
+    pub fn tctdbsetdfunit(tdb: TCTDB, dfunit: i32) -> bool;
+    pub fn tctdbsetinvcache(tdb: TCTDB, iccmax: i64, iccsync: f64) -> bool;
+    pub fn tctdbsetindex(tdb: TCTDB, name: *const c_char, itype: c_int) -> bool;
+    pub fn tctdbopen(tdb: TCTDB, path: *const c_char, omode: c_int) -> bool;
+    pub fn tctdbclose(tdb: TCTDB) -> bool;
+    pub fn tctdbput(tdb: TCTDB, pkbuf: *const u8, pksiz: c_int, cols: TCMAP) -> bool;
+    pub fn tctdbputkeep(tdb: TCTDB, pkbuf: *const u8, pksiz: c_int, cols: TCMAP) -> bool;
+    pub fn tctdbputcat(tdb: TCTDB, pkbuf: *const u8, pksiz: c_int, cols: TCMAP) -> bool;
+    pub fn tctdbout(tdb: TCTDB, pkbuf: *const u8, pksiz: c_int) -> bool;
+    pub fn tctdbget(tdb: TCTDB, pkbuf: *const u8, pksiz: c_int) -> TCMAP;
+    pub fn tctdbgenuid(tdb: TCTDB) -> i64;
+    pub fn tctdbiterinit(tdb: TCTDB) -> bool;
+    pub fn tctdbiternext(tdb: TCTDB, sp: *mut c_int) -> *mut u8;
+    pub fn tctdbsync(tdb: TCTDB) -> bool;
+    pub fn tctdboptimize(tdb: TCTDB, bnum: i64, apow: i8, fpow: i8, opts: u8) -> bool;
+    pub fn tctdbvanish(tdb: TCTDB) -> bool;
+    pub fn tctdbrnum(tdb: TCTDB) -> u64;
+    pub fn tctdbfsiz(tdb: TCTDB) -> u64;
+    pub fn tctdbqrynew(tdb: TCTDB) -> TDBQRY;
+    pub fn tctdbqrydel(qry: TDBQRY);
+    pub fn tctdbqryaddcond(qry: TDBQRY, name: *const c_char, op: c_int, expr: *const c_char);
+    pub fn tctdbqrysetorder(qry: TDBQRY, name: *const c_char, otype: c_int);
+    pub fn tctdbqrysetlimit(qry: TDBQRY, max: c_int, skip: c_int);
+    pub fn tctdbqrysearch(qry: TDBQRY) -> TCLIST;
+    pub fn tctdbqrysearchout(qry: TDBQRY) -> bool;
+    pub fn tctdbqryproc(qry: TDBQRY, procfn: TDBQRYPROC, op: *const u8) -> bool;
+    pub fn tctdbqrykwic(qry: TDBQRY, cols: TCMAP, name: *const c_char, width: c_int, opts: c_int) -> TCLIST;
+    pub fn tctdbqryhint(qry: TDBQRY) -> *const c_char;
+    pub fn tctdbqrystrtocondop(s: *const c_char) -> c_int;
+    pub fn tctdbqrystrtoordertype(s: *const c_char) -> c_int;
+    pub fn tctdbstrtoindextype(s: *const c_char) -> c_int;
+    pub fn tctdbmetasearch(qrys: *const TDBQRY, num: c_int, mstype: c_int) -> TCLIST;
+}
+
+/// Columns - The columns of a table record, by name.
+pub type Columns = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// TableTuning - Tuning applied to a table database before it is opened.
+///
+/// Parameters left unset keep the Tokyo Cabinet defaults.
+#[derive(Clone, Debug)]
+pub struct TableTuning {
+    bnum: Option<i64>,
+    apow: Option<i8>,
+    fpow: Option<i8>,
+    opts: Option<TuningOptions>,
+    rcnum: Option<i32>,
+    lcnum: Option<i32>,
+    ncnum: Option<i32>,
+    xmsiz: Option<i64>,
+    dfunit: Option<i32>,
+    invcache: Option<(i64, f64)>,
+}
+
+impl TableTuning {
+    /// Tuning with every parameter left at its default.
+    pub fn new() -> TableTuning {
+        TableTuning {
+            bnum: None,
+            apow: None,
+            fpow: None,
+            opts: None,
+            rcnum: None,
+            lcnum: None,
+            ncnum: None,
+            xmsiz: None,
+            dfunit: None,
+            invcache: None,
+        }
+    }
+
+    /// Number of elements of the bucket array.
+    pub fn bnum(mut self, bnum: i64) -> TableTuning {
+        self.bnum = Some(bnum);
+        self
+    }
+
+    /// Size of record alignment by power of 2.
+    pub fn apow(mut self, apow: i8) -> TableTuning {
+        self.apow = Some(apow);
+        self
+    }
+
+    /// Maximum number of elements of the free block pool by power of 2.
+    pub fn fpow(mut self, fpow: i8) -> TableTuning {
+        self.fpow = Some(fpow);
+        self
+    }
+
+    /// Tuning options.
+    pub fn opts(mut self, opts: TuningOptions) -> TableTuning {
+        self.opts = Some(opts);
+        self
+    }
+
+    /// Maximum number of records to be cached.
+    pub fn rcnum(mut self, rcnum: i32) -> TableTuning {
+        self.rcnum = Some(rcnum);
+        self
+    }
+
+    /// Maximum number of leaf nodes of the indexes to be cached.
+    pub fn lcnum(mut self, lcnum: i32) -> TableTuning {
+        self.lcnum = Some(lcnum);
+        self
+    }
+
+    /// Maximum number of non-leaf nodes of the indexes to be cached.
+    pub fn ncnum(mut self, ncnum: i32) -> TableTuning {
+        self.ncnum = Some(ncnum);
+        self
+    }
+
+    /// Size of the extra mapped memory.
+    pub fn xmsiz(mut self, xmsiz: i64) -> TableTuning {
+        self.xmsiz = Some(xmsiz);
+        self
+    }
+
+    /// Unit step number of auto defragmentation.
+    pub fn dfunit(mut self, dfunit: i32) -> TableTuning {
+        self.dfunit = Some(dfunit);
+        self
+    }
+
+    /// Maximum size of the inverted index cache, and the ratio of its
+    /// memory use at which it is synchronized with the file.
+    pub fn invcache(mut self, iccmax: i64, iccsync: f64) -> TableTuning {
+        self.invcache = Some((iccmax, iccsync));
+        self
+    }
+
+    fn apply(&self, db: &TableDb) -> Result<()> {
+        if self.bnum.is_some() || self.apow.is_some() || self.fpow.is_some() || self.opts.is_some() {
+            let opts = self.opts.map_or(0, |o| o.bits() as u8);
+            let ok = unsafe {
+                tctdbtune(db.db, self.bnum.unwrap_or(-1), self.apow.unwrap_or(-1),
+                          self.fpow.unwrap_or(-1), opts)
+            };
+            db.check(ok, "tctdbtune")?;
+        }
+        if self.rcnum.is_some() || self.lcnum.is_some() || self.ncnum.is_some() {
+            let ok = unsafe {
+                tctdbsetcache(db.db, self.rcnum.unwrap_or(-1), self.lcnum.unwrap_or(-1),
+                              self.ncnum.unwrap_or(-1))
+            };
+            db.check(ok, "tctdbsetcache")?;
+        }
+        if let Some(xmsiz) = self.xmsiz {
+            db.check(unsafe { tctdbsetxmsiz(db.db, xmsiz) }, "tctdbsetxmsiz")?;
+        }
+        if let Some(dfunit) = self.dfunit {
+            db.check(unsafe { tctdbsetdfunit(db.db, dfunit) }, "tctdbsetdfunit")?;
+        }
+        if let Some((iccmax, iccsync)) = self.invcache {
+            db.check(unsafe { tctdbsetinvcache(db.db, iccmax, iccsync) }, "tctdbsetinvcache")?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for TableTuning {
+    fn default() -> TableTuning {
+        TableTuning::new()
+    }
+}
+
+/// TableDbBuilder - Collects the open mode and tuning for a `TableDb`.
+#[derive(Clone)]
+pub struct TableDbBuilder {
+    modes: OpenModes,
+    tuning: TableTuning,
+}
+
+impl TableDbBuilder {
+    /// A builder opening the database as a reader with default tuning.
+    pub fn new() -> TableDbBuilder {
+        TableDbBuilder {
+            modes: TDBOREADER,
+            tuning: TableTuning::new(),
+        }
+    }
+
+    /// Set the open modes.
+    pub fn modes(mut self, modes: OpenModes) -> TableDbBuilder {
+        self.modes = modes;
+        self
+    }
+
+    /// Set the tuning applied before opening.
+    pub fn tuning(mut self, tuning: TableTuning) -> TableDbBuilder {
+        self.tuning = tuning;
+        self
+    }
+
+    /// Open the database file at `path`.
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<TableDb> {
+        let path = path.as_ref();
+        let cpath = path_to_cstring(path, "tctdbopen")?;
+        let mut db = TableDb { db: unsafe { tctdbnew() }, path: path.to_path_buf(), indexes: Vec::new() };
+        self.tuning.apply(&db)?;
+        let ok = unsafe { tctdbopen(db.db, cpath.as_ptr(), self.modes.bits()) };
+        db.check(ok, "tctdbopen")?;
+        db.indexes = find_indexes(path);
+        Ok(db)
+    }
+}
+
+impl Default for TableDbBuilder {
+    fn default() -> TableDbBuilder {
+        TableDbBuilder::new()
+    }
+}
+
+/// TableDb - An owned table database, whose records are sets of named columns.
+///
+/// The underlying `TCTDB` is closed and deleted when the `TableDb` is dropped.
+pub struct TableDb {
+    db: TCTDB,
+    pub(crate) path: PathBuf,
+    // Tokyo Cabinet has no call listing indexes, so keep track of those found
+    // at open and set since.
+    indexes: Vec<(String, IndexKind)>,
+}
+
+impl TableDb {
+    /// Start configuring a database to open.
+    pub fn builder() -> TableDbBuilder {
+        TableDbBuilder::new()
+    }
+
+    /// Open the database file at `path` with default tuning.
+    pub fn open<P: AsRef<Path>>(path: P, modes: OpenModes) -> Result<TableDb> {
+        TableDbBuilder::new().modes(modes).open(path)
+    }
+
+    /// Store a record, replacing any existing record with the same primary key.
+    pub fn put(&mut self, pk: &[u8], cols: &Columns) -> Result<()> {
+        let ok = self.with_map(cols, |db, map| unsafe { tctdbput(db, pk.as_ptr(), pk.len() as c_int, map) });
+        self.check(ok, "tctdbput")
+    }
+
+    /// Store a record unless the primary key already exists.
+    ///
+    /// Returns `false` if an existing record was kept.
+    pub fn put_keep(&mut self, pk: &[u8], cols: &Columns) -> Result<bool> {
+        let ok = self.with_map(cols, |db, map| unsafe { tctdbputkeep(db, pk.as_ptr(), pk.len() as c_int, map) });
+        self.check_found(ok, "tctdbputkeep", ErrorCode::Keep)
+    }
+
+    /// Add columns to a record, creating it if missing.
+    ///
+    /// Columns the record already has keep their existing values.
+    pub fn put_cat(&mut self, pk: &[u8], cols: &Columns) -> Result<()> {
+        let ok = self.with_map(cols, |db, map| unsafe { tctdbputcat(db, pk.as_ptr(), pk.len() as c_int, map) });
+        self.check(ok, "tctdbputcat")
+    }
+
+    /// Retrieve the columns of a record.
+    pub fn get(&self, pk: &[u8]) -> Result<Option<Columns>> {
+        unsafe {
+            let map = tctdbget(self.db, pk.as_ptr(), pk.len() as c_int);
+            if map.0.is_null() {
+                return self.check_found(false, "tctdbget", ErrorCode::NoRec).map(|_| None);
+            }
+            let cols = map_to_btree(map);
+            tcmapdel(map);
+            Ok(Some(cols))
+        }
+    }
+
+    /// Remove a record.
+    ///
+    /// Returns `false` if there was no such record.
+    pub fn remove(&mut self, pk: &[u8]) -> Result<bool> {
+        let ok = unsafe { tctdbout(self.db, pk.as_ptr(), pk.len() as c_int) };
+        self.check_found(ok, "tctdbout", ErrorCode::NoRec)
+    }
+
+    /// Generate a unique ID number, for use as a primary key.
+    pub fn gen_uid(&mut self) -> Result<i64> {
+        match unsafe { tctdbgenuid(self.db) } {
+            -1 => Err(self.last_error("tctdbgenuid")),
+            uid => Ok(uid),
+        }
+    }
+
+    /// Number of records.
+    pub fn len(&self) -> u64 {
+        unsafe { tctdbrnum(self.db) }
+    }
+
+    /// Whether there are no records.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Iterate over the primary key of every record.
+    pub fn keys(&mut self) -> Result<Keys<'_>> {
+        let ok = unsafe { tctdbiterinit(self.db) };
+        self.check(ok, "tctdbiterinit")?;
+        Ok(Keys { db: self, done: false })
+    }
+
+    /// Create, rebuild, optimize or remove the index of `column`, as `kind` says.
+    ///
+    /// Setting a lexical, decimal, token or q-gram index on a column that
+    /// already has one rebuilds it from scratch.
+    pub fn set_index(&mut self, column: &str, kind: IndexKind) -> Result<()> {
+        let name = CString::new(column)
+            .map_err(|_| TcError::new(ErrorCode::Invalid, "tctdbsetindex", Some(&self.path)))?;
+        let ok = unsafe { tctdbsetindex(self.db, name.as_ptr(), kind.as_raw()) };
+        self.check(ok, "tctdbsetindex")?;
+        match kind {
+            IndexKind::Optimize => {}
+            IndexKind::Void => self.indexes.retain(|(c, _)| c != column),
+            _ => self.note_index(column, kind),
+        }
+        Ok(())
+    }
+
+    /// Create the index of `column` unless it already has one.
+    ///
+    /// Returns `false` if an existing index was kept.
+    pub fn set_index_keep(&mut self, column: &str, kind: IndexKind) -> Result<bool> {
+        let name = CString::new(column)
+            .map_err(|_| TcError::new(ErrorCode::Invalid, "tctdbsetindex", Some(&self.path)))?;
+        let ok = unsafe { tctdbsetindex(self.db, name.as_ptr(), kind.as_raw() | TDBITKEEP) };
+        let created = self.check_found(ok, "tctdbsetindex", ErrorCode::Keep)?;
+        if created {
+            self.note_index(column, kind);
+        }
+        Ok(created)
+    }
+
+    /// The indexed columns, with the kind of each index.
+    ///
+    /// The indexes found beside the database file when it was opened come
+    /// first, by column name, followed by those set through this handle.
+    pub fn indexes(&self) -> &[(String, IndexKind)] {
+        &self.indexes
+    }
+
+    /// Rebuild every index listed by `indexes` from scratch.
+    pub fn rebuild_indexes(&mut self) -> Result<()> {
+        for (column, kind) in self.indexes.clone() {
+            self.set_index(&column, kind)?;
+        }
+        Ok(())
+    }
+
+    /// Optimize every index listed by `indexes`, compacting the files holding them.
+    pub fn optimize_indexes(&mut self) -> Result<()> {
+        for (column, _) in self.indexes.clone() {
+            self.set_index(&column, IndexKind::Optimize)?;
+        }
+        Ok(())
+    }
+
+    /// Start a query on the records.
+    pub fn query(&self) -> Query<'_> {
+        Query::new(self)
+    }
+
+    /// Start a query that can also remove the records it finds.
+    pub fn query_mut(&mut self) -> QueryMut<'_> {
+        QueryMut { query: Query::new(self) }
+    }
+
+    /// A query on the records written as text; see `Query::parse`.
+    pub fn parse_query(&self, text: &str) -> Result<Query<'_>> {
+        Query::parse(self, text)
+    }
+
+    /// Synchronize updated contents with the file and the device.
+    pub fn sync(&mut self) -> Result<()> {
+        let ok = unsafe { tctdbsync(self.db) };
+        self.check(ok, "tctdbsync")
+    }
+
+    /// Rebuild the database file, applying any of `bnum`, `apow`, `fpow` and
+    /// `opts` set in `tuning`.
+    pub fn optimize(&mut self, tuning: &TableTuning) -> Result<()> {
+        let opts = tuning.opts.map_or(u8::MAX, |o| o.bits() as u8);
+        let ok = unsafe {
+            tctdboptimize(self.db, tuning.bnum.unwrap_or(-1), tuning.apow.unwrap_or(-1),
+                          tuning.fpow.unwrap_or(-1), opts)
+        };
+        self.check(ok, "tctdboptimize")
+    }
+
+    /// Remove all records.
+    pub fn vanish(&mut self) -> Result<()> {
+        let ok = unsafe { tctdbvanish(self.db) };
+        self.check(ok, "tctdbvanish")
+    }
+
+    /// Close the database, reporting any error raised while doing so.
+    pub fn close(self) -> Result<()> {
+        let ok = unsafe { tctdbclose(self.db) };
+        self.check(ok, "tctdbclose")
+    }
+
+    /// The raw handle, for calling functions not wrapped here.
+    pub fn as_raw(&self) -> TCTDB {
+        self.db
+    }
+
+    // Run `f` on a temporary `TCMAP` copy of `cols`.
+    fn with_map<F: FnOnce(TCTDB, TCMAP) -> bool>(&self, cols: &Columns, f: F) -> bool {
+        unsafe {
+            let map = map_from_btree(cols);
+            let ok = f(self.db, map);
+            tcmapdel(map);
+            ok
+        }
+    }
+
+    fn note_index(&mut self, column: &str, kind: IndexKind) {
+        match self.indexes.iter_mut().find(|(c, _)| c == column) {
+            Some(entry) => entry.1 = kind,
+            None => self.indexes.push((column.to_string(), kind)),
+        }
+    }
+
+    fn last_error(&self, op: &'static str) -> TcError {
+        tcutil::last_error(tctdbecode, tctdberrmsg, self.db, &self.path, op)
+    }
+
+    fn check(&self, ok: bool, op: &'static str) -> Result<()> {
+        tcutil::check(tctdbecode, tctdberrmsg, self.db, &self.path, ok, op)
+    }
+
+    fn check_found(&self, ok: bool, op: &'static str, benign: ErrorCode) -> Result<bool> {
+        tcutil::check_found(tctdbecode, tctdberrmsg, self.db, &self.path, ok, op, benign)
+    }
+}
+
+impl Drop for TableDb {
+    fn drop(&mut self) {
+        unsafe { tctdbdel(self.db) };
+    }
+}
+
+// The indexes of the database at `path`, from the `<path>.idx.<column>.<kind>`
+// files Tokyo Cabinet keeps them in.
+fn find_indexes(path: &Path) -> Vec<(String, IndexKind)> {
+    let prefix = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => format!("{}.idx.", name),
+        None => return Vec::new(),
+    };
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut indexes: Vec<_> = entries
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter_map(|file| {
+            let rest = file.strip_prefix(&prefix)?;
+            let dot = rest.rfind('.')?;
+            let kind = match &rest[dot + 1..] {
+                "lex" => IndexKind::Lexical,
+                "dec" => IndexKind::Decimal,
+                "tok" => IndexKind::Token,
+                "qgr" => IndexKind::QGram,
+                _ => return None,
+            };
+            Some((rest[..dot].to_string(), kind))
+        })
+        .collect();
+    indexes.sort_by(|a, b| a.0.cmp(&b.0));
+    indexes
+}
+
+/// Keys - The primary keys of a `TableDb`, in storage order.
+///
+/// Each `tctdbiternext` call advances state stored in the `TableDb` itself,
+/// so no other walk may run until this is dropped.
+pub struct Keys<'a> {
+    db: &'a mut TableDb,
+    done: bool,
+}
+
+impl<'a> Iterator for Keys<'a> {
+    type Item = Result<TcBytes>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let mut size: c_int = 0;
+        let pkbuf = unsafe { tctdbiternext(self.db.db, &mut size) };
+        if !pkbuf.is_null() {
+            return Some(Ok(unsafe { TcBytes::from_raw(pkbuf, size as usize) }));
+        }
+        self.done = true;
+        match self.db.last_error("tctdbiternext") {
+            ref e if e.code() == ErrorCode::NoRec => None,
+            e => Some(Err(e)),
+        }
+    }
+}
+
+/// IndexKind - What `TableDb::set_index` does to the index of a column.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IndexKind {
+    /// index of the column as a string, for the `Str*` operators
+    Lexical,
+    /// index of the column as a number, for the `Num*` operators
+    Decimal,
+    /// inverted index of the space or comma separated tokens of the column
+    Token,
+    /// q-gram inverted index of the column, for full-text search
+    QGram,
+    /// optimize the existing index
+    Optimize,
+    /// remove the existing index
+    Void,
+}
+
+impl IndexKind {
+    /// The `TDBIT*` type.
+    pub fn as_raw(&self) -> c_int {
+        match *self {
+            IndexKind::Lexical => TDBITLEXICAL,
+            IndexKind::Decimal => TDBITDECIMAL,
+            IndexKind::Token => TDBITTOKEN,
+            IndexKind::QGram => TDBITQGRAM,
+            IndexKind::Optimize => TDBITOPT,
+            IndexKind::Void => TDBITVOID,
+        }
+    }
+
+    /// The kind of a stored index, from its `TDBIT*` type.
+    pub fn from_raw(itype: c_int) -> Option<IndexKind> {
+        match itype {
+            TDBITLEXICAL => Some(IndexKind::Lexical),
+            TDBITDECIMAL => Some(IndexKind::Decimal),
+            TDBITTOKEN => Some(IndexKind::Token),
+            TDBITQGRAM => Some(IndexKind::QGram),
+            TDBITOPT => Some(IndexKind::Optimize),
+            TDBITVOID => Some(IndexKind::Void),
+            _ => None,
+        }
+    }
+
+    /// Parse a type name such as `LEXICAL` or `QGRAM`, as `tctdbstrtoindextype` does.
+    ///
+    /// Names with the `+` prefix asking to keep an existing index are
+    /// rejected; use `TableDb::set_index_keep` instead.
+    pub fn from_name(name: &str) -> Option<IndexKind> {
+        let name = CString::new(name).ok()?;
+        IndexKind::from_raw(unsafe { tctdbstrtoindextype(name.as_ptr()) })
+    }
+}
+
+/// QueryOp - How a condition compares a column with its expression.
+///
+/// Operators taking several tokens expect them separated by spaces or commas.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum QueryOp {
+    /// string is equal to the expression
+    StrEq,
+    /// string includes the expression
+    StrInclude,
+    /// string begins with the expression
+    StrBeginsWith,
+    /// string ends with the expression
+    StrEndsWith,
+    /// string includes every token of the expression
+    StrAnd,
+    /// string includes at least one token of the expression
+    StrOr,
+    /// string is equal to at least one token of the expression
+    StrOrEq,
+    /// string matches the regular expression
+    StrRegex,
+    /// number is equal to the expression
+    NumEq,
+    /// number is greater than the expression
+    NumGt,
+    /// number is greater than or equal to the expression
+    NumGe,
+    /// number is less than the expression
+    NumLt,
+    /// number is less than or equal to the expression
+    NumLe,
+    /// number is between the two tokens of the expression, inclusive
+    NumBetween,
+    /// number is equal to at least one token of the expression
+    NumOrEq,
+    /// full-text search for the phrase
+    FtsPhrase,
+    /// full-text search for every token
+    FtsAnd,
+    /// full-text search for at least one token
+    FtsOr,
+    /// full-text search with a compound expression
+    FtsExpr,
+}
+
+impl QueryOp {
+    /// The `TDBQC*` operator.
+    pub fn as_raw(&self) -> c_int {
+        match *self {
+            QueryOp::StrEq => TDBQCSTREQ,
+            QueryOp::StrInclude => TDBQCSTRINC,
+            QueryOp::StrBeginsWith => TDBQCSTRBW,
+            QueryOp::StrEndsWith => TDBQCSTREW,
+            QueryOp::StrAnd => TDBQCSTRAND,
+            QueryOp::StrOr => TDBQCSTROR,
+            QueryOp::StrOrEq => TDBQCSTROREQ,
+            QueryOp::StrRegex => TDBQCSTRRX,
+            QueryOp::NumEq => TDBQCNUMEQ,
+            QueryOp::NumGt => TDBQCNUMGT,
+            QueryOp::NumGe => TDBQCNUMGE,
+            QueryOp::NumLt => TDBQCNUMLT,
+            QueryOp::NumLe => TDBQCNUMLE,
+            QueryOp::NumBetween => TDBQCNUMBT,
+            QueryOp::NumOrEq => TDBQCNUMOREQ,
+            QueryOp::FtsPhrase => TDBQCFTSPH,
+            QueryOp::FtsAnd => TDBQCFTSAND,
+            QueryOp::FtsOr => TDBQCFTSOR,
+            QueryOp::FtsExpr => TDBQCFTSEX,
+        }
+    }
+
+    /// The operator of a `TDBQC*` value without flags.
+    pub fn from_raw(op: c_int) -> Option<QueryOp> {
+        match op {
+            TDBQCSTREQ => Some(QueryOp::StrEq),
+            TDBQCSTRINC => Some(QueryOp::StrInclude),
+            TDBQCSTRBW => Some(QueryOp::StrBeginsWith),
+            TDBQCSTREW => Some(QueryOp::StrEndsWith),
+            TDBQCSTRAND => Some(QueryOp::StrAnd),
+            TDBQCSTROR => Some(QueryOp::StrOr),
+            TDBQCSTROREQ => Some(QueryOp::StrOrEq),
+            TDBQCSTRRX => Some(QueryOp::StrRegex),
+            TDBQCNUMEQ => Some(QueryOp::NumEq),
+            TDBQCNUMGT => Some(QueryOp::NumGt),
+            TDBQCNUMGE => Some(QueryOp::NumGe),
+            TDBQCNUMLT => Some(QueryOp::NumLt),
+            TDBQCNUMLE => Some(QueryOp::NumLe),
+            TDBQCNUMBT => Some(QueryOp::NumBetween),
+            TDBQCNUMOREQ => Some(QueryOp::NumOrEq),
+            TDBQCFTSPH => Some(QueryOp::FtsPhrase),
+            TDBQCFTSAND => Some(QueryOp::FtsAnd),
+            TDBQCFTSOR => Some(QueryOp::FtsOr),
+            TDBQCFTSEX => Some(QueryOp::FtsExpr),
+            _ => None,
+        }
+    }
+}
+
+/// Condition - A `QueryOp` with its negation and index flags.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Condition {
+    op: QueryOp,
+    negate: bool,
+    no_index: bool,
+}
+
+impl Condition {
+    /// A condition matching records for which `op` holds.
+    pub fn new(op: QueryOp) -> Condition {
+        Condition { op, negate: false, no_index: false }
+    }
+
+    /// Match the records for which the operator does not hold instead.
+    pub fn negate(mut self) -> Condition {
+        self.negate = true;
+        self
+    }
+
+    /// Scan the records rather than using an index of the column.
+    pub fn no_index(mut self) -> Condition {
+        self.no_index = true;
+        self
+    }
+
+    /// The operator.
+    pub fn op(&self) -> QueryOp {
+        self.op
+    }
+
+    /// The `TDBQC*` operator with its flags.
+    pub fn as_raw(&self) -> c_int {
+        let mut raw = self.op.as_raw();
+        if self.negate {
+            raw |= TDBQCNEGATE;
+        }
+        if self.no_index {
+            raw |= TDBQCNOIDX;
+        }
+        raw
+    }
+
+    /// The condition of a `TDBQC*` operator with its flags.
+    pub fn from_raw(raw: c_int) -> Option<Condition> {
+        let op = QueryOp::from_raw(raw & !(TDBQCNEGATE | TDBQCNOIDX))?;
+        Some(Condition { op, negate: raw & TDBQCNEGATE != 0, no_index: raw & TDBQCNOIDX != 0 })
+    }
+
+    /// Parse an operator name such as `STREQ`, `!NUMGE` or `+STRBW`, as
+    /// `tctdbqrystrtocondop` does.
+    pub fn from_name(name: &str) -> Option<Condition> {
+        let name = CString::new(name).ok()?;
+        Condition::from_raw(unsafe { tctdbqrystrtocondop(name.as_ptr()) })
+    }
+}
+
+impl From<QueryOp> for Condition {
+    fn from(op: QueryOp) -> Condition {
+        Condition::new(op)
+    }
+}
+
+/// QueryOrder - How a query sorts the records it finds.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum QueryOrder {
+    /// ascending order of the column as a string
+    StrAsc,
+    /// descending order of the column as a string
+    StrDesc,
+    /// ascending order of the column as a number
+    NumAsc,
+    /// descending order of the column as a number
+    NumDesc,
+}
+
+impl QueryOrder {
+    /// The `TDBQO*` order type.
+    pub fn as_raw(&self) -> c_int {
+        match *self {
+            QueryOrder::StrAsc => TDBQOSTRASC,
+            QueryOrder::StrDesc => TDBQOSTRDESC,
+            QueryOrder::NumAsc => TDBQONUMASC,
+            QueryOrder::NumDesc => TDBQONUMDESC,
+        }
+    }
+
+    /// The order of a `TDBQO*` type.
+    pub fn from_raw(otype: c_int) -> Option<QueryOrder> {
+        match otype {
+            TDBQOSTRASC => Some(QueryOrder::StrAsc),
+            TDBQOSTRDESC => Some(QueryOrder::StrDesc),
+            TDBQONUMASC => Some(QueryOrder::NumAsc),
+            TDBQONUMDESC => Some(QueryOrder::NumDesc),
+            _ => None,
+        }
+    }
+
+    /// Parse an order name such as `STRASC` or `NUMDESC`, as
+    /// `tctdbqrystrtoordertype` does.
+    pub fn from_name(name: &str) -> Option<QueryOrder> {
+        let name = CString::new(name).ok()?;
+        QueryOrder::from_raw(unsafe { tctdbqrystrtoordertype(name.as_ptr()) })
+    }
+}
+
+/// SetOp - How `Query::combine` merges the results of its queries.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SetOp {
+    /// records matching any of the queries
+    Union,
+    /// records matching every query
+    Intersection,
+    /// records matching the first query but none of the others
+    Difference,
+}
+
+impl SetOp {
+    /// The `TDBMS*` type.
+    pub fn as_raw(&self) -> c_int {
+        match *self {
+            SetOp::Union => TDBMSUNION,
+            SetOp::Intersection => TDBMSISECT,
+            SetOp::Difference => TDBMSDIFF,
+        }
+    }
+}
+
+/// QueryAction - What `QueryMut::for_each_mut` does with each record it visits.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum QueryAction {
+    /// leave the record unchanged and continue
+    Keep,
+    /// store the columns as modified by the closure and continue
+    Update,
+    /// remove the record and continue
+    Remove,
+    /// leave the record unchanged and visit no more records
+    Stop,
+}
+
+impl QueryAction {
+    /// The `TDBQP*` flags.
+    pub fn as_raw(&self) -> c_int {
+        match *self {
+            QueryAction::Keep => 0,
+            QueryAction::Update => TDBQPPUT,
+            QueryAction::Remove => TDBQPOUT,
+            QueryAction::Stop => TDBQPSTOP,
+        }
+    }
+}
+
+/// `TDBQRYPROC` calling the closure of the `Callback` that `op` points to.
+/// A panic stops the visit.
+unsafe extern "C" fn qry_proc_trampoline<F>(pkbuf: *const u8, pksiz: c_int,
+                                            cols: TCMAP, op: *const u8) -> c_int
+    where F: FnMut(&[u8], &mut Columns) -> QueryAction
+{
+    let cb = &mut *(op as *mut Callback<F>);
+    let f = &mut cb.f;
+    let pk = raw_slice(pkbuf, pksiz);
+    let mut columns = map_to_btree(cols);
+    let action = catch_panic(&mut cb.panic, QueryAction::Stop, || f(pk, &mut columns));
+    if action == QueryAction::Update {
+        map_assign(cols, &columns);
+    }
+    action.as_raw()
+}
+
+// The `TDBQRYPROC` and `op` pair to hand `cb` to `tctdbqryproc`.
+fn qry_proc_callback<F>(cb: &mut Callback<F>) -> (TDBQRYPROC, *const u8)
+    where F: FnMut(&[u8], &mut Columns) -> QueryAction
+{
+    (qry_proc_trampoline::<F>, cb as *mut Callback<F> as *const u8)
+}
+
+/// KwicMatch - A record found by `Query::search_kwic`, with its snippets.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KwicMatch {
+    /// the primary key
+    pub pk: Vec<u8>,
+    /// the columns of the record
+    pub columns: Columns,
+    /// the text around each keyword of the query found in the column
+    pub snippets: Vec<String>,
+}
+
+/// HintOrder - How a search put its results in order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HintOrder {
+    /// the results were left in storage order
+    Natural,
+    /// the results were read in order from the index of the column
+    Index(String),
+    /// the results were sorted by the column after they were found
+    Sorted(String),
+}
+
+/// QueryHint - What Tokyo Cabinet reports about how it ran a search.
+///
+/// Parsed from the text of `tctdbqryhint`, one line per step of the search.
+/// Only these lines are read; the others, such as the sizes of intermediate
+/// result sets, are kept in `text` alone:
+///
+/// - `using an index: "age" asc (NUMGE)`, where the index of the column was
+///   read `asc` or `desc`, or `inverted` for a token or q-gram index;
+/// - `scanning the whole table`;
+/// - `sorting the result set: "age"` and `leaving the natural order`;
+/// - `result set size: 2`.
+///
+/// Tokyo Cabinet does not report how many records it examined.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QueryHint {
+    /// the columns whose indexes were used, in the order they were used
+    pub indexes: Vec<String>,
+    /// whether every record was scanned
+    pub full_scan: bool,
+    /// the number of records the search returned, after the limit and offset
+    pub result_size: Option<usize>,
+    /// how the results were ordered: by the last index read `asc` or `desc`,
+    /// unless a later line says they were sorted or left in natural order
+    pub order: Option<HintOrder>,
+    /// the hint as Tokyo Cabinet wrote it
+    pub text: String,
+}
+
+impl QueryHint {
+    /// Parse the text of a hint.
+    pub fn parse(text: &str) -> QueryHint {
+        let mut hint = QueryHint { text: text.to_string(), ..QueryHint::default() };
+        for line in text.lines().map(str::trim) {
+            if let Some(rest) = line.strip_prefix("using an index: ") {
+                if let Some((column, direction)) = parse_hint_index(rest) {
+                    if direction == "asc" || direction == "desc" {
+                        hint.order = Some(HintOrder::Index(column.clone()));
+                    }
+                    hint.indexes.push(column);
+                }
+            } else if line.starts_with("scanning the whole table") {
+                hint.full_scan = true;
+            } else if let Some(rest) = line.strip_prefix("result set size: ") {
+                hint.result_size = rest.trim().parse().ok();
+            } else if line.starts_with("leaving the natural order") {
+                hint.order = Some(HintOrder::Natural);
+            } else if let Some(rest) = line.strip_prefix("sorting the result set: ") {
+                hint.order = Some(HintOrder::Sorted(rest.trim().trim_matches('"').to_string()));
+            }
+        }
+        hint
+    }
+
+    /// Whether the index of `column` was used.
+    pub fn uses_index(&self, column: &str) -> bool {
+        self.indexes.iter().any(|c| c == column)
+    }
+}
+
+// Split `"column" direction (OP)` into the column and the direction.
+fn parse_hint_index(rest: &str) -> Option<(String, &str)> {
+    let rest = rest.strip_prefix('"')?;
+    let end = rest.rfind('"')?;
+    let direction = rest[end + 1..].split_whitespace().next().unwrap_or("");
+    Some((rest[..end].to_string(), direction))
+}
+
+/// Query - A search of the records of a `TableDb`.
+///
+/// Conditions are all required to hold. The column named `""` is the
+/// primary key. A column name or expression containing a NUL byte is
+/// reported with `ErrorCode::Invalid` when the query is run.
+pub struct Query<'db> {
+    qry: TDBQRY,
+    db: &'db TableDb,
+    max: c_int,
+    skip: c_int,
+    error: Option<TcError>,
+}
+
+impl<'db> Query<'db> {
+    /// A query matching every record of `db`.
+    pub fn new(db: &'db TableDb) -> Query<'db> {
+        Query { qry: unsafe { tctdbqrynew(db.db) }, db, max: -1, skip: 0, error: None }
+    }
+
+    /// A query on `db` written as text, such as
+    /// `name STREQ alice AND age NUMGE 30 ORDER age NUMDESC LIMIT 10 OFFSET 20`.
+    ///
+    /// Conditions are `column operator expression`, joined by `AND`, and are
+    /// followed by any of `ORDER column type`, `LIMIT count` and `OFFSET count`.
+    /// Operators and order types take the names Tokyo Cabinet's own tools
+    /// accept (see `Condition::from_name`). Double quotes group a word
+    /// containing spaces, with `\` escaping `"` and `\`; the column `""` is
+    /// the primary key. Keywords are case-insensitive unless quoted.
+    pub fn parse(db: &'db TableDb, text: &str) -> Result<Query<'db>> {
+        let error = |message: String| {
+            TcError::with_message(ErrorCode::Invalid, "Query::parse", Some(&db.path), message)
+        };
+        let tokens = tokenize(text).map_err(&error)?;
+        let mut words = tokens.iter();
+        let mut query = Query::new(db);
+        let mut clause = Clause::Start;
+        while let Some(&(ref word, quoted)) = words.next() {
+            let keyword = if quoted { String::new() } else { word.to_ascii_uppercase() };
+            clause = match keyword.as_str() {
+                "AND" if clause == Clause::Cond => Clause::And,
+                "ORDER" if clause != Clause::And => {
+                    let column = next_word(&mut words, "column after ORDER", error)?;
+                    let otype = next_word(&mut words, "order type", error)?;
+                    let order = QueryOrder::from_name(otype)
+                        .ok_or_else(|| error(format!("unknown order type `{}`", otype)))?;
+                    query = query.order_by(column, order);
+                    Clause::Tail
+                }
+                "LIMIT" | "OFFSET" if clause != Clause::And => {
+                    let count = next_word(&mut words, "count", error)?;
+                    let count = count.parse::<usize>()
+                        .map_err(|_| error(format!("invalid {} `{}`", keyword, count)))?;
+                    query = if keyword == "LIMIT" { query.limit(count) } else { query.offset(count) };
+                    Clause::Tail
+                }
+                _ if clause == Clause::Start || clause == Clause::And => {
+                    let op = next_word(&mut words, "operator", error)?;
+                    let expr = next_word(&mut words, "expression", error)?;
+                    let cond = Condition::from_name(op)
+                        .ok_or_else(|| error(format!("unknown operator `{}`", op)))?;
+                    query = query.add_cond(word, cond, expr);
+                    Clause::Cond
+                }
+                _ => return Err(error(format!("unexpected `{}`", word))),
+            };
+        }
+        if clause == Clause::And {
+            return Err(error("expected condition after AND".to_string()));
+        }
+        Ok(query)
+    }
+
+    /// Require `cond` to hold between `column` and `expr`.
+    pub fn add_cond<C: Into<Condition>>(mut self, column: &str, cond: C, expr: &str) -> Query<'db> {
+        if let (Some(name), Some(expr)) = (self.cstring(column, "tctdbqryaddcond"),
+                                           self.cstring(expr, "tctdbqryaddcond")) {
+            unsafe { tctdbqryaddcond(self.qry, name.as_ptr(), cond.into().as_raw(), expr.as_ptr()) };
+        }
+        self
+    }
+
+    /// Sort the results by `column`.
+    pub fn order_by(mut self, column: &str, order: QueryOrder) -> Query<'db> {
+        if let Some(name) = self.cstring(column, "tctdbqrysetorder") {
+            unsafe { tctdbqrysetorder(self.qry, name.as_ptr(), order.as_raw()) };
+        }
+        self
+    }
+
+    /// Return at most `max` results.
+    pub fn limit(mut self, max: usize) -> Query<'db> {
+        self.max = to_c_int(max);
+        unsafe { tctdbqrysetlimit(self.qry, self.max, self.skip) };
+        self
+    }
+
+    /// Skip the first `skip` results.
+    pub fn offset(mut self, skip: usize) -> Query<'db> {
+        self.skip = to_c_int(skip);
+        unsafe { tctdbqrysetlimit(self.qry, self.max, self.skip) };
+        self
+    }
+
+    /// The primary keys of the matching records.
+    pub fn search(&self) -> Result<Vec<Vec<u8>>> {
+        self.check_built()?;
+        unsafe {
+            let pks = tctdbqrysearch(self.qry);
+            let result = list_to_vec(pks);
+            tclistdel(pks);
+            Ok(result)
+        }
+    }
+
+    /// The primary keys and columns of the matching records.
+    pub fn search_records(&self) -> Result<Vec<(Vec<u8>, Columns)>> {
+        let mut records = Vec::new();
+        for pk in self.search()? {
+            if let Some(cols) = self.db.get(&pk)? {
+                records.push((pk, cols));
+            }
+        }
+        Ok(records)
+    }
+
+    /// The primary keys found by combining the results of `queries` with `op`.
+    ///
+    /// The order and limit of the first query apply to the combined results.
+    /// Every query must be on the same database.
+    pub fn combine(queries: &[Query<'_>], op: SetOp) -> Result<Vec<Vec<u8>>> {
+        let first = match queries.first() {
+            Some(first) => first,
+            None => return Ok(Vec::new()),
+        };
+        for query in queries {
+            query.check_built()?;
+            if !ptr::eq(query.db, first.db) {
+                return Err(TcError::new(ErrorCode::Invalid, "tctdbmetasearch", Some(&first.db.path)));
+            }
+        }
+        let qrys = queries.iter().map(|q| q.qry).collect::<Vec<_>>();
+        unsafe {
+            let pks = tctdbmetasearch(qrys.as_ptr(), qrys.len() as c_int, op.as_raw());
+            let result = list_to_vec(pks);
+            tclistdel(pks);
+            Ok(result)
+        }
+    }
+
+    /// The matching records, each with keyword-in-context snippets of `column`.
+    ///
+    /// The keywords are the expressions of the full-text and `StrInclude`
+    /// conditions on `column`. Each snippet holds up to `width` characters on
+    /// either side of a keyword, or the whole text if `width` is `None`, marked
+    /// up as `opts` says.
+    pub fn search_kwic(&self, column: &str, width: Option<usize>, opts: KwicOptions) -> Result<Vec<KwicMatch>> {
+        let name = CString::new(column)
+            .map_err(|_| TcError::new(ErrorCode::Invalid, "tctdbqrykwic", Some(&self.db.path)))?;
+        let width = width.map_or(-1, to_c_int);
+        let mut matches = Vec::new();
+        for pk in self.search()? {
+            let (columns, snippets) = unsafe {
+                let map = tctdbget(self.db.db, pk.as_ptr(), pk.len() as c_int);
+                if map.0.is_null() {
+                    // Removed since the search; anything else is an error.
+                    self.db.check_found(false, "tctdbget", ErrorCode::NoRec)?;
+                    continue;
+                }
+                let list = tctdbqrykwic(self.qry, map, name.as_ptr(), width, opts.bits());
+                let columns = map_to_btree(map);
+                tcmapdel(map);
+                let snippets = list_to_vec(list);
+                tclistdel(list);
+                (columns, snippets)
+            };
+            let snippets = snippets.into_iter().map(|s| String::from_utf8_lossy(&s).into_owned()).collect();
+            matches.push(KwicMatch { pk, columns, snippets });
+        }
+        Ok(matches)
+    }
+
+    /// How the last search of this query was run.
+    ///
+    /// Empty until the query has been searched.
+    pub fn hint(&self) -> QueryHint {
+        let text = unsafe {
+            let ptr = tctdbqryhint(self.qry);
+            if ptr.is_null() {
+                return QueryHint::default();
+            }
+            CStr::from_ptr(ptr).to_string_lossy()
+        };
+        QueryHint::parse(&text)
+    }
+
+    /// The raw handle, for calling functions not wrapped here.
+    pub fn as_raw(&self) -> TDBQRY {
+        self.qry
+    }
+
+    // Keep the first NUL error, reporting it once the query is run.
+    fn cstring(&mut self, s: &str, op: &'static str) -> Option<CString> {
+        match CString::new(s) {
+            Ok(cs) => Some(cs),
+            Err(_) => {
+                if self.error.is_none() {
+                    self.error = Some(TcError::new(ErrorCode::Invalid, op, Some(&self.db.path)));
+                }
+                None
+            }
+        }
+    }
+
+    fn check_built(&self) -> Result<()> {
+        match self.error {
+            Some(ref e) => Err(e.clone()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<'db> Drop for Query<'db> {
+    fn drop(&mut self) {
+        unsafe { tctdbqrydel(self.qry) };
+    }
+}
+
+/// QueryMut - A `Query` holding the `TableDb` mutably, so it can also write.
+///
+/// Searches are those of `Query`, through `Deref`.
+pub struct QueryMut<'db> {
+    query: Query<'db>,
+}
+
+impl<'db> QueryMut<'db> {
+    /// Require `cond` to hold between `column` and `expr`.
+    pub fn add_cond<C: Into<Condition>>(self, column: &str, cond: C, expr: &str) -> QueryMut<'db> {
+        QueryMut { query: self.query.add_cond(column, cond, expr) }
+    }
+
+    /// Sort the results by `column`.
+    pub fn order_by(self, column: &str, order: QueryOrder) -> QueryMut<'db> {
+        QueryMut { query: self.query.order_by(column, order) }
+    }
+
+    /// Return at most `max` results.
+    pub fn limit(self, max: usize) -> QueryMut<'db> {
+        QueryMut { query: self.query.limit(max) }
+    }
+
+    /// Skip the first `skip` results.
+    pub fn offset(self, skip: usize) -> QueryMut<'db> {
+        QueryMut { query: self.query.offset(skip) }
+    }
+
+    /// Visit each matching record in turn, updating or removing it as `f` says.
+    ///
+    /// `f` receives the primary key and the columns of the record; to update
+    /// it, modify the columns and return `QueryAction::Update`. A panic in `f`
+    /// stops the visit and is resumed once `tctdbqryproc` has returned.
+    pub fn for_each_mut<F>(&mut self, f: F) -> Result<()>
+        where F: FnMut(&[u8], &mut Columns) -> QueryAction
+    {
+        self.query.check_built()?;
+        let mut cb = Callback::new(f);
+        let (procfn, op) = qry_proc_callback(&mut cb);
+        let ok = unsafe { tctdbqryproc(self.query.qry, procfn, op) };
+        cb.resume_panic();
+        self.query.db.check(ok, "tctdbqryproc")
+    }
+
+    /// Remove the matching records.
+    pub fn search_out(&mut self) -> Result<()> {
+        self.query.check_built()?;
+        let ok = unsafe { tctdbqrysearchout(self.query.qry) };
+        self.query.db.check(ok, "tctdbqrysearchout")
+    }
+}
+
+impl<'db> Deref for QueryMut<'db> {
+    type Target = Query<'db>;
+
+    fn deref(&self) -> &Query<'db> {
+        &self.query
+    }
+}
+
+// Where `Query::parse` is: a condition may come at the start or after `AND`,
+// and only `ORDER`, `LIMIT` and `OFFSET` may follow them.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Clause {
+    Start,
+    Cond,
+    And,
+    Tail,
+}
+
+// Split query text into words, each flagged whether it was quoted.
+fn tokenize(text: &str) -> result::Result<Vec<(String, bool)>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    loop {
+        while let Some(&c) = chars.peek() {
+            if !c.is_whitespace() {
+                break;
+            }
+            chars.next();
+        }
+        let mut word = String::new();
+        match chars.peek() {
+            None => return Ok(tokens),
+            Some(&'"') => {
+                chars.next();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) => word.push(c),
+                            None => return Err("unterminated quote".to_string()),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err("unterminated quote".to_string()),
+                    }
+                }
+                tokens.push((word, true));
+            }
+            Some(_) => {
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push((word, false));
+            }
+        }
+    }
+}
+
+// The next word of a query, or an error naming what was expected instead.
+fn next_word<'a, I, E>(words: &mut I, what: &str, error: E) -> Result<&'a str>
+    where I: Iterator<Item = &'a (String, bool)>, E: Fn(String) -> TcError
+{
+    match words.next() {
+        Some((word, _)) => Ok(word),
+        None => Err(error(format!("expected {} at end of query", what))),
+    }
+}
+
+fn to_c_int(n: usize) -> c_int {
+    cmp::min(n, c_int::MAX as usize) as c_int
+}
+
+#[cfg(test)]
+mod test {
+    use std::panic::{self, AssertUnwindSafe};
+    use tctdb::*;
+    use tcutil::{Callback, TCKWMUBRCT, TCKWNOOVER, map_from_btree, map_to_btree, tcmapdel};
+
+    fn columns(pairs: &[(&str, &str)]) -> Columns {
+        pairs.iter().map(|&(k, v)| (k.as_bytes().to_vec(), v.as_bytes().to_vec())).collect()
+    }
+
+    #[test]
+    fn test_new_del() {
+        unsafe {
+            let db = tctdbnew();
+            assert!(!db.0.is_null());
+            tctdbdel(db);
+        }
+    }
+
+    #[test]
+    fn test_tabledb_basics() {
+        let rustpath = ".tctdb_test_tabledb_basics.tct";
+        {
+            let mut db = TableDb::open(rustpath, TDBOWRITER | TDBOCREAT | TDBOTRUNC).unwrap();
+            let alice = columns(&[("name", "alice"), ("age", "30")]);
+            db.put(b"1", &alice).unwrap();
+            assert_eq!(db.get(b"1").unwrap().unwrap(), alice);
+            assert!(db.get(b"2").unwrap().is_none());
+            assert!(!db.put_keep(b"1", &columns(&[("name", "bob")])).unwrap());
+            assert!(db.put_keep(b"2", &columns(&[("name", "bob")])).unwrap());
+            db.put_cat(b"2", &columns(&[("name", "robert"), ("age", "40")])).unwrap();
+            assert_eq!(db.get(b"2").unwrap().unwrap(), columns(&[("name", "bob"), ("age", "40")]));
+            assert_eq!(db.len(), 2);
+            assert!(db.remove(b"1").unwrap());
+            assert!(!db.remove(b"1").unwrap());
+            db.sync().unwrap();
+            db.vanish().unwrap();
+            assert!(db.is_empty());
+            db.close().unwrap();
+        }
+        assert!(::std::fs::remove_file(rustpath).is_ok());
+    }
+
+    #[test]
+    fn test_tabledb_keys_and_uids() {
+        let rustpath = ".tctdb_test_tabledb_keys_and_uids.tct";
+        {
+            let tuning = TableTuning::new().bnum(10_007).opts(TDBTLARGE).rcnum(64).xmsiz(1 << 20);
+            let mut db = TableDb::builder()
+                .modes(TDBOWRITER | TDBOCREAT | TDBOTRUNC)
+                .tuning(tuning)
+                .open(rustpath)
+                .unwrap();
+            let first = db.gen_uid().unwrap();
+            let second = db.gen_uid().unwrap();
+            assert!(second > first);
+            for pk in &[first, second] {
+                db.put(pk.to_string().as_bytes(), &columns(&[("n", "x")])).unwrap();
+            }
+            let mut keys = db.keys().unwrap().map(|k| k.map(Vec::from)).collect::<Result<Vec<_>>>().unwrap();
+            keys.sort();
+            assert_eq!(keys, vec![first.to_string().into_bytes(), second.to_string().into_bytes()]);
+            db.optimize(&TableTuning::new()).unwrap();
+            assert_eq!(db.len(), 2);
+        }
+        assert!(::std::fs::remove_file(rustpath).is_ok());
+    }
+
+    #[test]
+    fn test_query_flags() {
+        assert_eq!(Condition::new(QueryOp::NumBetween).as_raw(), TDBQCNUMBT);
+        assert_eq!(Condition::new(QueryOp::StrEq).negate().no_index().as_raw(),
+                   TDBQCSTREQ | TDBQCNEGATE | TDBQCNOIDX);
+        assert_eq!(Condition::from(QueryOp::FtsExpr).op(), QueryOp::FtsExpr);
+        assert_eq!(QueryOrder::NumDesc.as_raw(), TDBQONUMDESC);
+    }
+
+    #[test]
+    fn test_query_search() {
+        let rustpath = ".tctdb_test_query_search.tct";
+        {
+            let mut db = TableDb::open(rustpath, TDBOWRITER | TDBOCREAT | TDBOTRUNC).unwrap();
+            db.put(b"1", &columns(&[("name", "alice"), ("age", "30")])).unwrap();
+            db.put(b"2", &columns(&[("name", "bob"), ("age", "25")])).unwrap();
+            db.put(b"3", &columns(&[("name", "carol"), ("age", "41")])).unwrap();
+
+            let pks = db.query()
+                .add_cond("age", QueryOp::NumBetween, "20 35")
+                .order_by("age", QueryOrder::NumAsc)
+                .search()
+                .unwrap();
+            assert_eq!(pks, vec![b"2".to_vec(), b"1".to_vec()]);
+
+            let pks = db.query()
+                .add_cond("name", Condition::new(QueryOp::StrBeginsWith).negate(), "a")
+                .order_by("name", QueryOrder::StrDesc)
+                .limit(1)
+                .offset(1)
+                .search()
+                .unwrap();
+            assert_eq!(pks, vec![b"2".to_vec()]);
+
+            let records = db.query().add_cond("name", QueryOp::StrRegex, "^c").search_records().unwrap();
+            assert_eq!(records, vec![(b"3".to_vec(), columns(&[("name", "carol"), ("age", "41")]))]);
+
+            let err = db.query().add_cond("na\0me", QueryOp::StrEq, "x").search().err().unwrap();
+            assert_eq!(err.code(), ErrorCode::Invalid);
+
+            let mut older = db.query_mut().add_cond("age", QueryOp::NumGe, "30");
+            assert_eq!(older.search().unwrap().len(), 2);
+            older.search_out().unwrap();
+            drop(older);
+            assert_eq!(db.len(), 1);
+        }
+        assert!(::std::fs::remove_file(rustpath).is_ok());
+    }
+
+    #[test]
+    fn test_tabledb_indexes() {
+        let rustpath = ".tctdb_test_tabledb_indexes.tct";
+        {
+            let mut db = TableDb::builder()
+                .modes(TDBOWRITER | TDBOCREAT | TDBOTRUNC)
+                .tuning(TableTuning::new().invcache(1 << 20, 0.5))
+                .open(rustpath)
+                .unwrap();
+            db.put(b"1", &columns(&[("name", "alice"), ("age", "30")])).unwrap();
+            assert!(db.indexes().is_empty());
+            db.set_index("name", IndexKind::Lexical).unwrap();
+            assert!(db.set_index_keep("age", IndexKind::Decimal).unwrap());
+            assert!(!db.set_index_keep("age", IndexKind::Decimal).unwrap());
+            assert_eq!(db.indexes(), vec![("name".to_string(), IndexKind::Lexical),
+                                          ("age".to_string(), IndexKind::Decimal)]);
+            db.rebuild_indexes().unwrap();
+            db.optimize_indexes().unwrap();
+            assert_eq!(db.query().add_cond("age", QueryOp::NumEq, "30").search().unwrap(), vec![b"1".to_vec()]);
+            db.set_index("name", IndexKind::Void).unwrap();
+            assert_eq!(db.indexes(), vec![("age".to_string(), IndexKind::Decimal)]);
+        }
+        {
+            let mut db = TableDb::open(rustpath, TDBOWRITER).unwrap();
+            assert_eq!(db.indexes(), vec![("age".to_string(), IndexKind::Decimal)]);
+            assert!(!db.set_index_keep("age", IndexKind::Lexical).unwrap());
+            assert_eq!(db.indexes(), vec![("age".to_string(), IndexKind::Decimal)]);
+            assert!(db.set_index_keep("name", IndexKind::Token).unwrap());
+            assert_eq!(db.indexes(), vec![("age".to_string(), IndexKind::Decimal),
+                                          ("name".to_string(), IndexKind::Token)]);
+        }
+        for suffix in &["", ".idx.age.dec", ".idx.name.tok"] {
+            assert!(::std::fs::remove_file(format!("{}{}", rustpath, suffix)).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_query_combine() {
+        let rustpath = ".tctdb_test_query_combine.tct";
+        {
+            let mut db = TableDb::open(rustpath, TDBOWRITER | TDBOCREAT | TDBOTRUNC).unwrap();
+            db.put(b"1", &columns(&[("name", "alice"), ("age", "30")])).unwrap();
+            db.put(b"2", &columns(&[("name", "bob"), ("age", "25")])).unwrap();
+            db.put(b"3", &columns(&[("name", "carol"), ("age", "41")])).unwrap();
+
+            let young = || db.query().add_cond("age", QueryOp::NumLt, "35").order_by("", QueryOrder::StrAsc);
+            let named_c = || db.query().add_cond("name", QueryOp::StrBeginsWith, "c");
+            let has_o = || db.query().add_cond("name", QueryOp::StrInclude, "o");
+
+            assert_eq!(Query::combine(&[young(), named_c()], SetOp::Union).unwrap(),
+                       vec![b"1".to_vec(), b"2".to_vec(), b"3".to_vec()]);
+            assert_eq!(Query::combine(&[young(), has_o()], SetOp::Intersection).unwrap(), vec![b"2".to_vec()]);
+            assert_eq!(Query::combine(&[young(), has_o()], SetOp::Difference).unwrap(), vec![b"1".to_vec()]);
+            assert!(Query::combine(&[], SetOp::Union).unwrap().is_empty());
+
+            let other = TableDb::open(".tctdb_test_query_combine_other.tct", TDBOWRITER | TDBOCREAT | TDBOTRUNC).unwrap();
+            let err = Query::combine(&[young(), other.query()], SetOp::Union).err().unwrap();
+            assert_eq!(err.code(), ErrorCode::Invalid);
+        }
+        assert!(::std::fs::remove_file(rustpath).is_ok());
+        assert!(::std::fs::remove_file(".tctdb_test_query_combine_other.tct").is_ok());
+    }
+
+    #[test]
+    fn test_query_for_each_mut() {
+        let rustpath = ".tctdb_test_query_for_each_mut.tct";
+        {
+            let mut db = TableDb::open(rustpath, TDBOWRITER | TDBOCREAT | TDBOTRUNC).unwrap();
+            for (pk, age) in &[("1", "30"), ("2", "25"), ("3", "41"), ("4", "52")] {
+                db.put(pk.as_bytes(), &columns(&[("age", age)])).unwrap();
+            }
+
+            let mut seen = Vec::new();
+            db.query_mut().order_by("", QueryOrder::StrAsc).for_each_mut(|pk, cols| {
+                seen.push(pk.to_vec());
+                match pk {
+                    b"1" => {
+                        cols.insert(b"age".to_vec(), b"31".to_vec());
+                        QueryAction::Update
+                    }
+                    b"2" => QueryAction::Remove,
+                    b"3" => QueryAction::Stop,
+                    _ => QueryAction::Keep,
+                }
+            }).unwrap();
+            assert_eq!(seen, vec![b"1".to_vec(), b"2".to_vec(), b"3".to_vec()]);
+            assert_eq!(db.get(b"1").unwrap().unwrap(), columns(&[("age", "31")]));
+            assert!(db.get(b"2").unwrap().is_none());
+            assert_eq!(db.len(), 3);
+
+            // A panic stops the visit and reaches the caller; keep its message
+            // out of the test output.
+            let hook = panic::take_hook();
+            panic::set_hook(Box::new(|_| {}));
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                let mut older = db.query_mut().add_cond("age", QueryOp::NumGt, "40");
+                older.for_each_mut(|_, _| panic!("boom"))
+            }));
+            panic::set_hook(hook);
+            assert!(result.is_err());
+            assert_eq!(db.len(), 3);
+        }
+        assert!(::std::fs::remove_file(rustpath).is_ok());
+    }
+
+    #[test]
+    fn test_qry_proc_trampoline() {
+        let mut visit = Callback::new(|pk: &[u8], cols: &mut Columns| {
+            if pk == b"keep" {
+                cols.clear();
+                return QueryAction::Keep;
+            }
+            cols.insert(b"seen".to_vec(), pk.to_vec());
+            QueryAction::Update
+        });
+        let (procfn, op) = qry_proc_callback(&mut visit);
+        let mut cols = Columns::new();
+        cols.insert(b"name".to_vec(), b"alice".to_vec());
+        unsafe {
+            let map = map_from_btree(&cols);
+            assert_eq!(procfn(b"keep".as_ptr(), 4, map, op), QueryAction::Keep.as_raw());
+            assert_eq!(map_to_btree(map), cols);
+            assert_eq!(procfn(b"pk".as_ptr(), 2, map, op), QueryAction::Update.as_raw());
+            cols.insert(b"seen".to_vec(), b"pk".to_vec());
+            assert_eq!(map_to_btree(map), cols);
+            tcmapdel(map);
+        }
+    }
+
+    #[test]
+    fn test_query_search_kwic() {
+        let rustpath = ".tctdb_test_query_search_kwic.tct";
+        {
+            let mut db = TableDb::open(rustpath, TDBOWRITER | TDBOCREAT | TDBOTRUNC).unwrap();
+            db.set_index("text", IndexKind::QGram).unwrap();
+            db.put(b"1", &columns(&[("text", "the quick brown fox jumps over the lazy dog")])).unwrap();
+            db.put(b"2", &columns(&[("text", "a slow green turtle")])).unwrap();
+            db.put(b"3", &columns(&[("text", "the fox and the hound")])).unwrap();
+
+            let matches = db.query()
+                .add_cond("text", QueryOp::FtsAnd, "fox the")
+                .order_by("", QueryOrder::StrAsc)
+                .search_kwic("text", Some(4), TCKWMUBRCT | TCKWNOOVER)
+                .unwrap();
+            assert_eq!(matches.iter().map(|m| &m.pk[..]).collect::<Vec<_>>(), vec![&b"1"[..], b"3"]);
+            assert!(matches.iter().all(|m| m.snippets.iter().any(|s| s.contains("[fox]"))));
+            assert_eq!(matches[1].columns, columns(&[("text", "the fox and the hound")]));
+
+            let phrase = db.query().add_cond("text", QueryOp::FtsPhrase, "lazy dog")
+                .search_kwic("text", None, KwicOptions::empty())
+                .unwrap();
+            assert_eq!(phrase.len(), 1);
+            assert!(phrase[0].snippets.iter().any(|s| s.contains("quick brown fox")));
+
+            let none = db.query().add_cond("text", QueryOp::FtsOr, "cat mouse").search_kwic("text", Some(4), KwicOptions::empty());
+            assert!(none.unwrap().is_empty());
+        }
+        assert!(::std::fs::remove_file(rustpath).is_ok());
+        assert!(::std::fs::remove_file(format!("{}.idx.text.qgr", rustpath)).is_ok());
+    }
+
+    #[test]
+    fn test_query_hint_parse() {
+        let text = "using an index: \"name\" asc (STRBW)\n\
+                    result set size: 12\n\
+                    sorting the result set: \"age\"\n";
+        let hint = QueryHint::parse(text);
+        assert_eq!(hint.indexes, vec!["name".to_string()]);
+        assert!(hint.uses_index("name"));
+        assert!(!hint.full_scan);
+        assert_eq!(hint.result_size, Some(12));
+        assert_eq!(hint.order, Some(HintOrder::Sorted("age".to_string())));
+
+        let hint = QueryHint::parse("using an index: \"age\" desc (NUMDESC)\nresult set size: 3\n");
+        assert_eq!(hint.order, Some(HintOrder::Index("age".to_string())));
+
+        // An equality or range condition on an indexed column, ordered by it.
+        let text = "using an index: \"age\" asc (NUMGE)\n\
+                    auxiliary result set size: 2\n\
+                    result set size: 1\n";
+        let hint = QueryHint::parse(text);
+        assert_eq!(hint.indexes, vec!["age".to_string()]);
+        assert_eq!(hint.result_size, Some(1));
+        assert_eq!(hint.order, Some(HintOrder::Index("age".to_string())));
+
+        let hint = QueryHint::parse("using an index: \"text\" inverted (FTSAND)\nresult set size: 4\n");
+        assert!(hint.uses_index("text"));
+        assert_eq!(hint.order, None);
+
+        let hint = QueryHint::parse("scanning the whole table\nresult set size: 0\nleaving the natural order\n");
+        assert!(hint.full_scan);
+        assert!(hint.indexes.is_empty());
+        assert_eq!(hint.order, Some(HintOrder::Natural));
+        assert_eq!(QueryHint::parse(""), QueryHint::default());
+    }
+
+    #[test]
+    fn test_query_hint() {
+        let rustpath = ".tctdb_test_query_hint.tct";
+        {
+            let mut db = TableDb::open(rustpath, TDBOWRITER | TDBOCREAT | TDBOTRUNC).unwrap();
+            db.set_index("name", IndexKind::Lexical).unwrap();
+            db.put(b"1", &columns(&[("name", "alice"), ("age", "30")])).unwrap();
+            db.put(b"2", &columns(&[("name", "bob"), ("age", "25")])).unwrap();
+
+            let indexed = db.query().add_cond("name", QueryOp::StrEq, "bob");
+            assert_eq!(indexed.hint(), QueryHint::default());
+            assert_eq!(indexed.search().unwrap(), vec![b"2".to_vec()]);
+            let hint = indexed.hint();
+            assert!(hint.uses_index("name"), "{}", hint.text);
+            assert!(!hint.full_scan);
+
+            let scanned = db.query().add_cond("age", QueryOp::NumGt, "20");
+            assert_eq!(scanned.search().unwrap().len(), 2);
+            let hint = scanned.hint();
+            assert!(hint.indexes.is_empty(), "{}", hint.text);
+            assert!(hint.full_scan, "{}", hint.text);
+            drop((indexed, scanned));
+
+            db.set_index("age", IndexKind::Decimal).unwrap();
+            db.put(b"3", &columns(&[("name", "carol"), ("age", "41")])).unwrap();
+            let ordered = db.query()
+                .add_cond("age", QueryOp::NumGe, "26")
+                .order_by("age", QueryOrder::NumAsc)
+                .limit(1);
+            assert_eq!(ordered.search().unwrap(), vec![b"1".to_vec()]);
+            let hint = ordered.hint();
+            assert!(hint.text.lines().count() > 1, "{}", hint.text);
+            assert_eq!(hint.indexes, vec!["age".to_string()], "{}", hint.text);
+            assert!(!hint.full_scan, "{}", hint.text);
+            assert_eq!(hint.order, Some(HintOrder::Index("age".to_string())), "{}", hint.text);
+            assert_eq!(hint.result_size, Some(1), "{}", hint.text);
+        }
+        assert!(::std::fs::remove_file(rustpath).is_ok());
+        for suffix in &[".idx.name.lex", ".idx.age.dec"] {
+            assert!(::std::fs::remove_file(format!("{}{}", rustpath, suffix)).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_query_names() {
+        assert_eq!(Condition::from_name("STREQ"), Some(Condition::new(QueryOp::StrEq)));
+        assert_eq!(Condition::from_name("numbt"), Some(Condition::new(QueryOp::NumBetween)));
+        assert_eq!(Condition::from_name("!STRBW"), Some(Condition::new(QueryOp::StrBeginsWith).negate()));
+        assert_eq!(Condition::from_name("+FTSEX"), Some(Condition::new(QueryOp::FtsExpr).no_index()));
+        assert_eq!(Condition::from_name("BOGUS"), None);
+        assert_eq!(QueryOrder::from_name("NUMDESC"), Some(QueryOrder::NumDesc));
+        assert_eq!(QueryOrder::from_name("sideways"), None);
+        assert_eq!(IndexKind::from_name("QGRAM"), Some(IndexKind::QGram));
+        assert_eq!(IndexKind::from_name("LEXICAL"), Some(IndexKind::Lexical));
+        assert_eq!(IndexKind::from_name("+LEXICAL"), None);
+        assert_eq!(IndexKind::from_name("nothing"), None);
+    }
+
+    #[test]
+    fn test_query_parse() {
+        let rustpath = ".tctdb_test_query_parse.tct";
+        {
+            let mut db = TableDb::open(rustpath, TDBOWRITER | TDBOCREAT | TDBOTRUNC).unwrap();
+            db.put(b"1", &columns(&[("name", "alice"), ("age", "30")])).unwrap();
+            db.put(b"2", &columns(&[("name", "bob"), ("age", "25")])).unwrap();
+            db.put(b"3", &columns(&[("name", "carol ann"), ("age", "41")])).unwrap();
+            db.put(b"4", &columns(&[("name", "alice"), ("age", "52")])).unwrap();
+
+            let search = |text: &str| db.parse_query(text).unwrap().search().unwrap();
+            assert_eq!(search("name STREQ alice AND age NUMGE 30 ORDER age NUMDESC LIMIT 10"),
+                       vec![b"4".to_vec(), b"1".to_vec()]);
+            assert_eq!(search("age numbt \"20 35\" order \"\" STRASC limit 1 offset 1"), vec![b"2".to_vec()]);
+            assert_eq!(search("name STREQ \"carol ann\""), vec![b"3".to_vec()]);
+            assert_eq!(search("name !STREQ alice ORDER \"\" STRASC"), vec![b"2".to_vec(), b"3".to_vec()]);
+            assert_eq!(search("").len(), 4);
+
+            for bad in &["name", "name STREQ", "name BOGUS x", "name STREQ x AND", "name STREQ x age STREQ y",
+                         "ORDER age SIDEWAYS", "LIMIT ten", "LIMIT 1 name STREQ x", "name STREQ \"x"] {
+                let err = db.parse_query(bad).err().unwrap();
+                assert_eq!(err.code(), ErrorCode::Invalid, "{}", bad);
+                assert_eq!(err.op(), "Query::parse");
+            }
+        }
+        assert!(::std::fs::remove_file(rustpath).is_ok());
+    }
+}
@@ -1,4 +1,19 @@
 use libc::{c_char, c_int, c_void, size_t};
+use std::any::Any;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::error;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::ops::Deref;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::ptr;
+use std::result;
+use std::slice;
+
+
 /// TCXSTR - Extensible String
 #[derive(Copy, Clone)]
 #[repr(C)]
@@ -17,6 +32,20 @@ pub struct TCLISTDATUM{
 #[repr(C)]
 pub struct TCLIST(pub *const c_void);
 
+/// TCMAP - Hash map.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct TCMAP(pub *const c_void);
+
+bitflags! {
+    flags KwicOptions : c_int {
+        const TCKWMUTAB  = 1 << 0,  // mark up by tabs
+        const TCKWMUCTRL = 1 << 1,  // mark up by control characters
+        const TCKWMUBRCT = 1 << 2,  // mark up by square brackets
+        const TCKWNOOVER = 1 << 24, // no overlap
+        const TCKWPULEAD = 1 << 25  // pick up the lead string
+    }
+}
 
 #[allow(dead_code)]
 #[link(name = "tokyocabinet")]
@@ -27,16 +56,17 @@ extern {
     pub fn tcmemdup(ptr: *const c_void, size: size_t) -> *const c_void;
     pub fn tcstrdup(str: *const c_void) -> *const c_char;
     pub fn tcfree(ptr: *const c_void);
+    pub fn tcerrmsg(ecode: c_int) -> *const c_char;
 
     pub fn tcxstrnew() -> TCXSTR;
     pub fn tcxstrnew2(str: *const c_char)-> TCXSTR;
     pub fn tcxstrnew3(asiz: c_int) -> TCXSTR;
-    pub fn tcxstrdup(xstr: &TCXSTR) -> TCXSTR;
-    pub fn tcxstrdel(xstr: &TCXSTR);
-    pub fn tcxstrcat(xstr: &TCXSTR, ptr: *const u8, size: c_int);
-    pub fn tcxstrcat2(xstr: &TCXSTR, str: *const c_char);
-    pub fn tcxstrptr(xstr: &TCXSTR) -> *const u8;
-    pub fn tcxstrsize(xstr: &TCXSTR) -> c_int;
+    pub fn tcxstrdup(xstr: TCXSTR) -> TCXSTR;
+    pub fn tcxstrdel(xstr: TCXSTR);
+    pub fn tcxstrcat(xstr: TCXSTR, ptr: *const u8, size: c_int);
+    pub fn tcxstrcat2(xstr: TCXSTR, str: *const c_char);
+    pub fn tcxstrptr(xstr: TCXSTR) -> *const u8;
+    pub fn tcxstrsize(xstr: TCXSTR) -> c_int;
     pub fn tcxstrclear(xstr: TCXSTR);
     //pub fn tcxstrprintf(TCXSTR *xstr, const char *format, ...);
     //pub fn tcsprintf(const char *format, ...) -> *const c_char;
@@ -44,63 +74,829 @@ extern {
     pub fn tclistnew() -> TCLIST;
     pub fn tclistnew2(anum: c_int) -> TCLIST;
     //pub fn tclistnew3(...) -> TCLIST;
-    pub fn tclistdup(list: &TCLIST) -> TCLIST;
+    pub fn tclistdup(list: TCLIST) -> TCLIST;
     pub fn tclistdel(list: TCLIST);
-    pub fn tclistnum(list: &TCLIST) -> c_int;
-
-    pub fn tclistval(list: &TCLIST, index: c_int, sp: *const c_int) -> *const u8;
-
-    pub fn tclistval2(list: &TCLIST, index: c_int)-> *const c_char;
-    pub fn tclistpush(list: &TCLIST, ptr: *const u8, size: c_int);
-    pub fn tclistpush2(list: &TCLIST, str: *const c_char);
-    pub fn tclistpop(list: &TCLIST, sp: *const c_int) -> *const u8;
-    pub fn tclistpop2(list: &TCLIST) -> *const u8;
-    pub fn tclistunshift(list: &TCLIST, ptr: *const u8, size: c_int);
-    pub fn tclistunshift2(list: &TCLIST, str: *const c_char);
-    pub fn tclistinsert(list: &TCLIST, index: c_int, ptr: *const u8, size: c_int);
-    pub fn tclistinsert2(list: &TCLIST, index: c_int, str: *const c_char);
-    pub fn tclistremove(list: &TCLIST, index: c_int, sp: *const c_int) -> *const u8;
-    pub fn tclistremove2(list: &TCLIST, index: c_int) -> *const c_char;
-    pub fn tclistover(list: &TCLIST, index: c_int, ptr: *const u8, size: c_int);
-    pub fn tclistover2(list: &TCLIST, index: c_int, str: *const c_char);
-    pub fn tclistsort(list: &mut TCLIST);
-    pub fn tclistlsearch(list: &TCLIST, ptr: *const u8, size: c_int) -> c_int;
-    pub fn tclistbsearch(list: &TCLIST, ptr: *const u8, size: c_int) -> c_int;
-    pub fn tclistclear(list: &mut TCLIST);
-    pub fn tclistdump(list: &TCLIST, sp: *const c_int)-> *const u8;
+    pub fn tclistnum(list: TCLIST) -> c_int;
+
+    pub fn tclistval(list: TCLIST, index: c_int, sp: *mut c_int) -> *const u8;
+
+    pub fn tclistval2(list: TCLIST, index: c_int)-> *const c_char;
+    pub fn tclistpush(list: TCLIST, ptr: *const u8, size: c_int);
+    pub fn tclistpush2(list: TCLIST, str: *const c_char);
+    pub fn tclistpop(list: TCLIST, sp: *mut c_int) -> *mut u8;
+    pub fn tclistpop2(list: TCLIST) -> *mut c_char;
+    pub fn tclistunshift(list: TCLIST, ptr: *const u8, size: c_int);
+    pub fn tclistunshift2(list: TCLIST, str: *const c_char);
+    pub fn tclistinsert(list: TCLIST, index: c_int, ptr: *const u8, size: c_int);
+    pub fn tclistinsert2(list: TCLIST, index: c_int, str: *const c_char);
+    pub fn tclistremove(list: TCLIST, index: c_int, sp: *mut c_int) -> *mut u8;
+    pub fn tclistremove2(list: TCLIST, index: c_int) -> *mut c_char;
+    pub fn tclistover(list: TCLIST, index: c_int, ptr: *const u8, size: c_int);
+    pub fn tclistover2(list: TCLIST, index: c_int, str: *const c_char);
+    pub fn tclistsort(list: TCLIST);
+    pub fn tclistlsearch(list: TCLIST, ptr: *const u8, size: c_int) -> c_int;
+    pub fn tclistbsearch(list: TCLIST, ptr: *const u8, size: c_int) -> c_int;
+    pub fn tclistclear(list: TCLIST);
+    pub fn tclistdump(list: TCLIST, sp: *mut c_int)-> *mut u8;
     pub fn tclistload(ptr: *const u8, size: c_int) -> TCLIST;
+
+    pub fn tcmapnew() -> TCMAP;
+    pub fn tcmapnew2(bnum: u32) -> TCMAP;
+    pub fn tcmapdup(map: TCMAP) -> TCMAP;
+    pub fn tcmapdel(map: TCMAP);
+    pub fn tcmapput(map: TCMAP, kbuf: *const u8, ksiz: c_int, vbuf: *const u8, vsiz: c_int);
+    pub fn tcmapput2(map: TCMAP, kstr: *const c_char, vstr: *const c_char);
+    pub fn tcmapputkeep(map: TCMAP, kbuf: *const u8, ksiz: c_int, vbuf: *const u8, vsiz: c_int) -> bool;
+    pub fn tcmapout(map: TCMAP, kbuf: *const u8, ksiz: c_int) -> bool;
+    pub fn tcmapget(map: TCMAP, kbuf: *const u8, ksiz: c_int, sp: *mut c_int) -> *const u8;
+    pub fn tcmapget2(map: TCMAP, kstr: *const c_char) -> *const c_char;
+    pub fn tcmapiterinit(map: TCMAP);
+    pub fn tcmapiternext(map: TCMAP, sp: *mut c_int) -> *const u8;
+    pub fn tcmapiterval(kbuf: *const u8, sp: *mut c_int) -> *const u8;
+    pub fn tcmaprnum(map: TCMAP) -> u64;
+    pub fn tcmapmsiz(map: TCMAP) -> u64;
+    pub fn tcmapclear(map: TCMAP);
 }
 
-pub type TCCMP = extern "C" fn(aptr: *const c_char, asiz: c_int, bptr: *const c_char, bsiz: c_int, op: *const u8);
-pub type TCCODEC = extern "C" fn(ptr: *const u8, size: c_int, sp: *const c_int, op: *const u8);
-pub type TCPDPROC = extern "C" fn(vbuf: *const u8, vsiz: c_int, sp: *const c_int, op: *const u8);
-pub type TCITER = extern "C" fn(kbuf: *const u8, ksiz: c_int, vbuf: *const u8, vsiz: c_int, op: *const u8);
+/// Copy the contents of an extensible string.
+pub(crate) unsafe fn xstr_to_vec(xstr: TCXSTR) -> Vec<u8> {
+    raw_slice(tcxstrptr(xstr), tcxstrsize(xstr)).to_vec()
+}
 
-bitflags! {
-    flags ErrorCodes : c_int {
-        const TCESUCCESS = 0,     /* success */
-        const TCETHREAD  = 1,     /* threading error */
-        const TCEINVALID = 2,     /* invalid operation */
-        const TCENOFILE  = 3,     /* file not found */
-        const TCENOPERM  = 4,     /* no permission */
-        const TCEMETA    = 5,     /* invalid meta data */
-        const TCERHEAD   = 6,     /* invalid record header */
-        const TCEOPEN    = 7,     /* open error */
-        const TCECLOSE   = 8,     /* close error */
-        const TCETRUNC   = 9,     /* trunc error */
-        const TCESYNC    = 10,    /* sync error */
-        const TCESTAT    = 11,    /* stat error */
-        const TCESEEK    = 12,    /* seek error */
-        const TCEREAD    = 13,    /* read error */
-        const TCEWRITE   = 14,    /* write error */
-        const TCEMMAP    = 15,    /* mmap error */
-        const TCELOCK    = 16,    /* lock error */
-        const TCEUNLINK  = 17,    /* unlink error */
-        const TCERENAME  = 18,    /* rename error */
-        const TCEMKDIR   = 19,    /* mkdir error */
-        const TCERMDIR   = 20,    /* rmdir error */
-        const TCEKEEP    = 21,    /* existing record */
-        const TCENOREC   = 22,    /* no record found */
-        const TCEMISC    = 9999   /* miscellaneous error */
+/// View the element at `index` of a list.
+pub(crate) unsafe fn list_val<'a>(list: TCLIST, index: c_int) -> &'a [u8] {
+    let mut size: c_int = 0;
+    let vbuf = tclistval(list, index, &mut size);
+    raw_slice(vbuf, size)
+}
+
+/// Copy every element of a list.
+pub(crate) unsafe fn list_to_vec(list: TCLIST) -> Vec<Vec<u8>> {
+    (0..tclistnum(list)).map(|i| list_val(list, i).to_vec()).collect()
+}
+
+/// A new list holding a copy of each of `vals`; the caller must `tclistdel` it.
+pub(crate) unsafe fn list_from_slices<V: AsRef<[u8]>>(vals: &[V]) -> TCLIST {
+    let list = tclistnew2(vals.len() as c_int);
+    for val in vals {
+        let val = val.as_ref();
+        tclistpush(list, val.as_ptr(), val.len() as c_int);
+    }
+    list
+}
+
+/// Copy every record of a map, resetting its iterator.
+pub(crate) unsafe fn map_to_btree(map: TCMAP) -> BTreeMap<Vec<u8>, Vec<u8>> {
+    let mut result = BTreeMap::new();
+    tcmapiterinit(map);
+    loop {
+        let mut ksiz: c_int = 0;
+        let kbuf = tcmapiternext(map, &mut ksiz);
+        if kbuf.is_null() {
+            return result;
+        }
+        let mut vsiz: c_int = 0;
+        let vbuf = tcmapiterval(kbuf, &mut vsiz);
+        result.insert(raw_slice(kbuf, ksiz).to_vec(), raw_slice(vbuf, vsiz).to_vec());
+    }
+}
+
+/// A new map holding a copy of each of `records`; the caller must `tcmapdel` it.
+pub(crate) unsafe fn map_from_btree(records: &BTreeMap<Vec<u8>, Vec<u8>>) -> TCMAP {
+    let map = tcmapnew2(records.len() as u32 + 1);
+    map_assign(map, records);
+    map
+}
+
+/// Replace the records of a map with a copy of `records`.
+pub(crate) unsafe fn map_assign(map: TCMAP, records: &BTreeMap<Vec<u8>, Vec<u8>>) {
+    tcmapclear(map);
+    for (k, v) in records {
+        tcmapput(map, k.as_ptr(), k.len() as c_int, v.as_ptr(), v.len() as c_int);
+    }
+}
+
+/// TcBytes - A region allocated by Tokyo Cabinet, freed with `tcfree` on drop.
+pub struct TcBytes {
+    ptr: *mut u8,
+    len: usize,
+}
+
+unsafe impl Send for TcBytes {}
+unsafe impl Sync for TcBytes {}
+
+impl TcBytes {
+    /// Take ownership of a region returned by a Tokyo Cabinet function.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be non-null, hold at least `len` bytes and be safe to pass
+    /// to `tcfree`.
+    pub unsafe fn from_raw(ptr: *mut u8, len: usize) -> TcBytes {
+        TcBytes { ptr, len }
+    }
+
+    /// Copy the bytes into a `Vec`, freeing the original region.
+    pub fn into_vec(self) -> Vec<u8> {
+        self.to_vec()
+    }
+}
+
+impl Drop for TcBytes {
+    fn drop(&mut self) {
+        unsafe { tcfree(self.ptr as *const c_void) };
+    }
+}
+
+impl Deref for TcBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl AsRef<[u8]> for TcBytes {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl fmt::Debug for TcBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl PartialEq for TcBytes {
+    fn eq(&self, other: &TcBytes) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for TcBytes {}
+
+impl PartialEq<[u8]> for TcBytes {
+    fn eq(&self, other: &[u8]) -> bool {
+        **self == *other
+    }
+}
+
+impl<'a> PartialEq<&'a [u8]> for TcBytes {
+    fn eq(&self, other: &&'a [u8]) -> bool {
+        **self == **other
+    }
+}
+
+impl PartialEq<Vec<u8>> for TcBytes {
+    fn eq(&self, other: &Vec<u8>) -> bool {
+        **self == other[..]
+    }
+}
+
+impl From<TcBytes> for Vec<u8> {
+    fn from(bytes: TcBytes) -> Vec<u8> {
+        bytes.into_vec()
+    }
+}
+
+/// Compare two keys, returning negative, zero or positive like `memcmp`.
+pub type TCCMP = unsafe extern "C" fn(aptr: *const c_char, asiz: c_int, bptr: *const c_char, bsiz: c_int, op: *const u8) -> c_int;
+/// Encode or decode a region, returning a `tcmalloc`ed result (or `NULL` on failure) and its size via `sp`.
+pub type TCCODEC = unsafe extern "C" fn(ptr: *const u8, size: c_int, sp: *mut c_int, op: *const u8) -> *mut u8;
+/// Compute the new value of an existing record; `NULL` keeps it and `-1` removes it.
+pub type TCPDPROC = unsafe extern "C" fn(vbuf: *const u8, vsiz: c_int, sp: *mut c_int, op: *const u8) -> *mut u8;
+/// Visit a record, returning `false` to stop iterating.
+pub type TCITER = unsafe extern "C" fn(kbuf: *const u8, ksiz: c_int, vbuf: *const u8, vsiz: c_int, op: *const u8) -> bool;
+
+/// The outcome of an update procedure passed through `TCPDPROC`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProcAction {
+    /// leave the record unchanged
+    Keep,
+    /// replace the value of the record
+    Replace(Vec<u8>),
+    /// remove the record
+    Remove,
+}
+
+/// Callback - A closure handed to Tokyo Cabinet, with a slot for its panic.
+///
+/// Unwinding out of an `extern "C"` callback aborts the process, so the
+/// trampolines below catch a panic, park its payload here and return a
+/// fallback. Call `resume_panic` once the C call has returned.
+pub struct Callback<F> {
+    pub(crate) f: F,
+    pub(crate) panic: Option<Box<dyn Any + Send>>,
+}
+
+impl<F> Callback<F> {
+    pub fn new(f: F) -> Callback<F> {
+        Callback { f, panic: None }
+    }
+
+    /// Re-raise the panic the closure hit during the last C call, if any.
+    pub fn resume_panic(&mut self) {
+        if let Some(payload) = self.panic.take() {
+            panic::resume_unwind(payload);
+        }
+    }
+}
+
+/// Run `f`, returning `fallback` instead if it panics. The first payload
+/// caught is kept in `slot`.
+pub(crate) fn catch_panic<R, F: FnOnce() -> R>(slot: &mut Option<Box<dyn Any + Send>>,
+                                               fallback: R, f: F) -> R {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => result,
+        Err(payload) => {
+            slot.get_or_insert(payload);
+            fallback
+        }
+    }
+}
+
+/// View a region passed to a callback as a slice.
+pub(crate) unsafe fn raw_slice<'a>(ptr: *const u8, size: c_int) -> &'a [u8] {
+    if ptr.is_null() || size <= 0 {
+        &[]
+    } else {
+        slice::from_raw_parts(ptr, size as usize)
+    }
+}
+
+/// Copy `bytes` into a fresh `tcmalloc`ed region, as callbacks must return.
+pub(crate) unsafe fn tc_copy(bytes: &[u8], sp: *mut c_int) -> *mut u8 {
+    let buf = tcmalloc(bytes.len() as size_t + 1) as *mut u8;
+    ptr::copy_nonoverlapping(bytes.as_ptr(), buf, bytes.len());
+    *buf.add(bytes.len()) = 0;
+    *sp = bytes.len() as c_int;
+    buf
+}
+
+/// `TCCMP` calling the `F` that `op` points to. A panic aborts the process.
+///
+/// No ordering is a safe fallback: answering "equal" mid-operation would let
+/// Tokyo Cabinet merge or misplace keys and corrupt the tree on disk.
+unsafe extern "C" fn cmp_trampoline<F>(aptr: *const c_char, asiz: c_int,
+                                       bptr: *const c_char, bsiz: c_int,
+                                       op: *const u8) -> c_int
+    where F: Fn(&[u8], &[u8]) -> Ordering
+{
+    let f = &*(op as *const F);
+    let a = raw_slice(aptr as *const u8, asiz);
+    let b = raw_slice(bptr as *const u8, bsiz);
+    panic::catch_unwind(AssertUnwindSafe(|| f(a, b) as c_int))
+        .unwrap_or_else(|_| process::abort())
+}
+
+/// `TCCODEC` calling the `F` that `op` points to. `None` or a panic fails the codec.
+unsafe extern "C" fn codec_trampoline<F>(ptr: *const u8, size: c_int,
+                                         sp: *mut c_int, op: *const u8) -> *mut u8
+    where F: Fn(&[u8]) -> Option<Vec<u8>>
+{
+    let cb = &mut *(op as *mut Callback<F>);
+    let f = &cb.f;
+    let input = raw_slice(ptr, size);
+    match catch_panic(&mut cb.panic, None, || f(input)) {
+        Some(output) => tc_copy(&output, sp),
+        None => ptr::null_mut(),
+    }
+}
+
+/// `TCPDPROC` calling the `F` that `op` points to. A panic keeps the record.
+unsafe extern "C" fn proc_trampoline<F>(vbuf: *const u8, vsiz: c_int,
+                                        sp: *mut c_int, op: *const u8) -> *mut u8
+    where F: FnMut(&[u8]) -> ProcAction
+{
+    let cb = &mut *(op as *mut Callback<F>);
+    let f = &mut cb.f;
+    let value = raw_slice(vbuf, vsiz);
+    match catch_panic(&mut cb.panic, ProcAction::Keep, || f(value)) {
+        ProcAction::Keep => ptr::null_mut(),
+        ProcAction::Replace(value) => tc_copy(&value, sp),
+        ProcAction::Remove => -1isize as *mut u8,
+    }
+}
+
+/// `TCITER` calling the `F` that `op` points to. A panic stops the iteration.
+unsafe extern "C" fn iter_trampoline<F>(kbuf: *const u8, ksiz: c_int,
+                                        vbuf: *const u8, vsiz: c_int,
+                                        op: *const u8) -> bool
+    where F: FnMut(&[u8], &[u8]) -> bool
+{
+    let cb = &mut *(op as *mut Callback<F>);
+    let f = &mut cb.f;
+    let key = raw_slice(kbuf, ksiz);
+    let value = raw_slice(vbuf, vsiz);
+    catch_panic(&mut cb.panic, false, || f(key, value))
+}
+
+/// The `TCCMP` and `op` pair to hand `f` to Tokyo Cabinet.
+///
+/// `f` must outlive every call Tokyo Cabinet makes through the pair. If `f`
+/// panics the process is aborted, since no comparison result would leave the
+/// database consistent.
+pub fn cmp_callback<F>(f: &F) -> (TCCMP, *const u8)
+    where F: Fn(&[u8], &[u8]) -> Ordering
+{
+    (cmp_trampoline::<F>, f as *const F as *const u8)
+}
+
+/// The `TCCODEC` and `op` pair to hand `f` to Tokyo Cabinet.
+///
+/// `cb` must outlive every call Tokyo Cabinet makes through the pair, and
+/// its `resume_panic` be called after each.
+pub fn codec_callback<F>(cb: &mut Callback<F>) -> (TCCODEC, *const u8)
+    where F: Fn(&[u8]) -> Option<Vec<u8>>
+{
+    (codec_trampoline::<F>, cb as *mut Callback<F> as *const u8)
+}
+
+/// The `TCPDPROC` and `op` pair to hand `f` to Tokyo Cabinet.
+///
+/// `cb` must outlive every call Tokyo Cabinet makes through the pair, and
+/// its `resume_panic` be called after each.
+pub fn proc_callback<F>(cb: &mut Callback<F>) -> (TCPDPROC, *const u8)
+    where F: FnMut(&[u8]) -> ProcAction
+{
+    (proc_trampoline::<F>, cb as *mut Callback<F> as *const u8)
+}
+
+/// The `TCITER` and `op` pair to hand `f` to Tokyo Cabinet.
+///
+/// `cb` must outlive every call Tokyo Cabinet makes through the pair, and
+/// its `resume_panic` be called after each.
+pub fn iter_callback<F>(cb: &mut Callback<F>) -> (TCITER, *const u8)
+    where F: FnMut(&[u8], &[u8]) -> bool
+{
+    (iter_trampoline::<F>, cb as *mut Callback<F> as *const u8)
+}
+
+pub const TCESUCCESS: c_int = 0;     /* success */
+pub const TCETHREAD: c_int = 1;      /* threading error */
+pub const TCEINVALID: c_int = 2;     /* invalid operation */
+pub const TCENOFILE: c_int = 3;      /* file not found */
+pub const TCENOPERM: c_int = 4;      /* no permission */
+pub const TCEMETA: c_int = 5;        /* invalid meta data */
+pub const TCERHEAD: c_int = 6;       /* invalid record header */
+pub const TCEOPEN: c_int = 7;        /* open error */
+pub const TCECLOSE: c_int = 8;       /* close error */
+pub const TCETRUNC: c_int = 9;       /* trunc error */
+pub const TCESYNC: c_int = 10;       /* sync error */
+pub const TCESTAT: c_int = 11;       /* stat error */
+pub const TCESEEK: c_int = 12;       /* seek error */
+pub const TCEREAD: c_int = 13;       /* read error */
+pub const TCEWRITE: c_int = 14;      /* write error */
+pub const TCEMMAP: c_int = 15;       /* mmap error */
+pub const TCELOCK: c_int = 16;       /* lock error */
+pub const TCEUNLINK: c_int = 17;     /* unlink error */
+pub const TCERENAME: c_int = 18;     /* rename error */
+pub const TCEMKDIR: c_int = 19;      /* mkdir error */
+pub const TCERMDIR: c_int = 20;      /* rmdir error */
+pub const TCEKEEP: c_int = 21;       /* existing record */
+pub const TCENOREC: c_int = 22;      /* no record found */
+pub const TCEMISC: c_int = 9999;     /* miscellaneous error */
+
+/// ErrorCode - The error code of a failed operation.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    /// success
+    Success,
+    /// threading error
+    Thread,
+    /// invalid operation
+    Invalid,
+    /// file not found
+    NoFile,
+    /// no permission
+    NoPerm,
+    /// invalid meta data
+    Meta,
+    /// invalid record header
+    RHead,
+    /// open error
+    Open,
+    /// close error
+    Close,
+    /// trunc error
+    Trunc,
+    /// sync error
+    Sync,
+    /// stat error
+    Stat,
+    /// seek error
+    Seek,
+    /// read error
+    Read,
+    /// write error
+    Write,
+    /// mmap error
+    Mmap,
+    /// lock error
+    Lock,
+    /// unlink error
+    Unlink,
+    /// rename error
+    Rename,
+    /// mkdir error
+    Mkdir,
+    /// rmdir error
+    Rmdir,
+    /// existing record
+    Keep,
+    /// no record found
+    NoRec,
+    /// miscellaneous error
+    Misc,
+    /// a code this crate does not know about
+    Unknown(c_int),
+}
+
+impl ErrorCode {
+    /// The error code for a raw `TCE*` value.
+    pub fn from_raw(ecode: c_int) -> ErrorCode {
+        match ecode {
+            TCESUCCESS => ErrorCode::Success,
+            TCETHREAD => ErrorCode::Thread,
+            TCEINVALID => ErrorCode::Invalid,
+            TCENOFILE => ErrorCode::NoFile,
+            TCENOPERM => ErrorCode::NoPerm,
+            TCEMETA => ErrorCode::Meta,
+            TCERHEAD => ErrorCode::RHead,
+            TCEOPEN => ErrorCode::Open,
+            TCECLOSE => ErrorCode::Close,
+            TCETRUNC => ErrorCode::Trunc,
+            TCESYNC => ErrorCode::Sync,
+            TCESTAT => ErrorCode::Stat,
+            TCESEEK => ErrorCode::Seek,
+            TCEREAD => ErrorCode::Read,
+            TCEWRITE => ErrorCode::Write,
+            TCEMMAP => ErrorCode::Mmap,
+            TCELOCK => ErrorCode::Lock,
+            TCEUNLINK => ErrorCode::Unlink,
+            TCERENAME => ErrorCode::Rename,
+            TCEMKDIR => ErrorCode::Mkdir,
+            TCERMDIR => ErrorCode::Rmdir,
+            TCEKEEP => ErrorCode::Keep,
+            TCENOREC => ErrorCode::NoRec,
+            TCEMISC => ErrorCode::Misc,
+            other => ErrorCode::Unknown(other),
+        }
+    }
+
+    /// The raw `TCE*` value of the error code.
+    pub fn to_raw(&self) -> c_int {
+        match *self {
+            ErrorCode::Success => TCESUCCESS,
+            ErrorCode::Thread => TCETHREAD,
+            ErrorCode::Invalid => TCEINVALID,
+            ErrorCode::NoFile => TCENOFILE,
+            ErrorCode::NoPerm => TCENOPERM,
+            ErrorCode::Meta => TCEMETA,
+            ErrorCode::RHead => TCERHEAD,
+            ErrorCode::Open => TCEOPEN,
+            ErrorCode::Close => TCECLOSE,
+            ErrorCode::Trunc => TCETRUNC,
+            ErrorCode::Sync => TCESYNC,
+            ErrorCode::Stat => TCESTAT,
+            ErrorCode::Seek => TCESEEK,
+            ErrorCode::Read => TCEREAD,
+            ErrorCode::Write => TCEWRITE,
+            ErrorCode::Mmap => TCEMMAP,
+            ErrorCode::Lock => TCELOCK,
+            ErrorCode::Unlink => TCEUNLINK,
+            ErrorCode::Rename => TCERENAME,
+            ErrorCode::Mkdir => TCEMKDIR,
+            ErrorCode::Rmdir => TCERMDIR,
+            ErrorCode::Keep => TCEKEEP,
+            ErrorCode::NoRec => TCENOREC,
+            ErrorCode::Misc => TCEMISC,
+            ErrorCode::Unknown(other) => other,
+        }
+    }
+}
+
+/// Signature shared by `tcerrmsg` and the per-backend `*errmsg` functions.
+pub type ErrMsgFn = unsafe extern "C" fn(ecode: c_int) -> *const c_char;
+
+/// Signature of the per-backend `*ecode` functions, over the handle type `H`.
+pub(crate) type EcodeFn<H> = unsafe extern "C" fn(db: H) -> c_int;
+
+/// The last error recorded on `db`, read through its backend's functions.
+pub(crate) fn last_error<H>(ecode: EcodeFn<H>, errmsg: ErrMsgFn, db: H,
+                            path: &Path, op: &'static str) -> TcError {
+    let code = unsafe { ecode(db) };
+    TcError::with_errmsg(code, errmsg, op, Some(path))
+}
+
+/// `Ok(())` if `ok`, otherwise the last error recorded on `db`.
+pub(crate) fn check<H>(ecode: EcodeFn<H>, errmsg: ErrMsgFn, db: H,
+                       path: &Path, ok: bool, op: &'static str) -> Result<()> {
+    if ok { Ok(()) } else { Err(last_error(ecode, errmsg, db, path, op)) }
+}
+
+/// Like `check`, but the expected "soft" failure `benign` becomes `Ok(false)`.
+pub(crate) fn check_found<H>(ecode: EcodeFn<H>, errmsg: ErrMsgFn, db: H,
+                             path: &Path, ok: bool, op: &'static str,
+                             benign: ErrorCode) -> Result<bool> {
+    if ok {
+        return Ok(true);
+    }
+    match last_error(ecode, errmsg, db, path, op) {
+        ref e if e.code() == benign => Ok(false),
+        e => Err(e),
+    }
+}
+
+/// ErrorContext - The operation, database path and message of a `TcError`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ErrorContext {
+    op: &'static str,
+    path: Option<PathBuf>,
+    message: String,
+}
+
+/// TcError - A failed operation on a database, one variant per `TCE*` code.
+///
+/// Every variant carries the `ErrorContext` of the failure; `code()` gives
+/// the variant as a plain `ErrorCode` for comparisons.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TcError {
+    /// success
+    Success(ErrorContext),
+    /// threading error
+    Thread(ErrorContext),
+    /// invalid operation
+    Invalid(ErrorContext),
+    /// file not found
+    NoFile(ErrorContext),
+    /// no permission
+    NoPerm(ErrorContext),
+    /// invalid meta data
+    Meta(ErrorContext),
+    /// invalid record header
+    RHead(ErrorContext),
+    /// open error
+    Open(ErrorContext),
+    /// close error
+    Close(ErrorContext),
+    /// trunc error
+    Trunc(ErrorContext),
+    /// sync error
+    Sync(ErrorContext),
+    /// stat error
+    Stat(ErrorContext),
+    /// seek error
+    Seek(ErrorContext),
+    /// read error
+    Read(ErrorContext),
+    /// write error
+    Write(ErrorContext),
+    /// mmap error
+    Mmap(ErrorContext),
+    /// lock error
+    Lock(ErrorContext),
+    /// unlink error
+    Unlink(ErrorContext),
+    /// rename error
+    Rename(ErrorContext),
+    /// mkdir error
+    Mkdir(ErrorContext),
+    /// rmdir error
+    Rmdir(ErrorContext),
+    /// existing record
+    Keep(ErrorContext),
+    /// no record found
+    NoRec(ErrorContext),
+    /// miscellaneous error
+    Misc(ErrorContext),
+    /// a code this crate does not know about
+    Unknown(c_int, ErrorContext),
+}
+
+macro_rules! error_variants {
+    ($($variant:ident),*) => {
+        impl TcError {
+            fn from_parts(code: ErrorCode, cx: ErrorContext) -> TcError {
+                match code {
+                    $(ErrorCode::$variant => TcError::$variant(cx),)*
+                    ErrorCode::Unknown(ecode) => TcError::Unknown(ecode, cx),
+                }
+            }
+
+            /// The error code.
+            pub fn code(&self) -> ErrorCode {
+                match *self {
+                    $(TcError::$variant(_) => ErrorCode::$variant,)*
+                    TcError::Unknown(ecode, _) => ErrorCode::Unknown(ecode),
+                }
+            }
+
+            fn context(&self) -> &ErrorContext {
+                match *self {
+                    $(TcError::$variant(ref cx))|* | TcError::Unknown(_, ref cx) => cx,
+                }
+            }
+        }
+    }
+}
+
+error_variants!(
+    Success, Thread, Invalid, NoFile, NoPerm, Meta, RHead, Open, Close, Trunc, Sync, Stat,
+    Seek, Read, Write, Mmap, Lock, Unlink, Rename, Mkdir, Rmdir, Keep, NoRec, Misc
+);
+
+impl TcError {
+    /// An error detected before reaching Tokyo Cabinet, described by `tcerrmsg`.
+    pub fn new(code: ErrorCode, op: &'static str, path: Option<&Path>) -> TcError {
+        TcError::with_errmsg(code.to_raw(), tcerrmsg, op, path)
+    }
+
+    /// An error code reported by a backend, described by its `errmsg` function.
+    pub fn with_errmsg(ecode: c_int, errmsg: ErrMsgFn, op: &'static str,
+                       path: Option<&Path>) -> TcError {
+        let msg = unsafe { errmsg(ecode) };
+        let message = if msg.is_null() {
+            String::from("unknown error")
+        } else {
+            unsafe { CStr::from_ptr(msg) }.to_string_lossy().into_owned()
+        };
+        TcError::with_message(ErrorCode::from_raw(ecode), op, path, message)
+    }
+
+    /// An error detected before reaching Tokyo Cabinet, with its own description.
+    pub fn with_message(code: ErrorCode, op: &'static str, path: Option<&Path>, message: String) -> TcError {
+        TcError::from_parts(code, ErrorContext { op, path: path.map(Path::to_path_buf), message })
+    }
+
+    /// The function that failed, such as `tchdbput`.
+    pub fn op(&self) -> &str {
+        self.context().op
+    }
+
+    /// The path of the database the operation was applied to, if any.
+    pub fn path(&self) -> Option<&Path> {
+        self.context().path.as_deref()
+    }
+
+    /// The message Tokyo Cabinet gives for the error code, or the description
+    /// given to `with_message`.
+    pub fn message(&self) -> &str {
+        &self.context().message
+    }
+}
+
+impl fmt::Display for TcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let cx = self.context();
+        match cx.path {
+            Some(ref path) => write!(f, "{} on {}: {}", cx.op, path.display(), cx.message),
+            None => write!(f, "{}: {}", cx.op, cx.message),
+        }
+    }
+}
+
+impl error::Error for TcError {}
+
+/// Result of the safe database wrappers.
+pub type Result<T> = result::Result<T, TcError>;
+
+/// Convert a database path into the C string expected by the `*open` functions.
+pub fn path_to_cstring(path: &Path, op: &'static str) -> Result<CString> {
+    match path.to_str().and_then(|s| CString::new(s).ok()) {
+        Some(cpath) => Ok(cpath),
+        None => Err(TcError::new(ErrorCode::Invalid, op, Some(path))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use libc::{c_char, c_int, c_void};
+    use std::ptr;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::path::Path;
+    use std::slice;
+    use tcutil::*;
+
+    #[test]
+    fn test_cmp_trampoline() {
+        let by_len = |a: &[u8], b: &[u8]| a.len().cmp(&b.len());
+        let (cmp, op) = cmp_callback(&by_len);
+        let (a, b) = (b"zz", b"aaa");
+        unsafe {
+            assert!(cmp(a.as_ptr() as *const c_char, 2, b.as_ptr() as *const c_char, 3, op) < 0);
+            assert!(cmp(b.as_ptr() as *const c_char, 3, a.as_ptr() as *const c_char, 2, op) > 0);
+            assert_eq!(cmp(ptr::null(), 0, ptr::null(), 0, op), 0);
+        }
+    }
+
+    #[test]
+    fn test_codec_and_proc_trampolines() {
+        let mut upper = Callback::new(|input: &[u8]| Some(input.to_ascii_uppercase()));
+        let (codec, op) = codec_callback(&mut upper);
+        let mut size: c_int = 0;
+        unsafe {
+            let out = codec(b"abc".as_ptr(), 3, &mut size, op);
+            assert!(!out.is_null());
+            assert_eq!(slice::from_raw_parts(out, size as usize), b"ABC");
+            tcfree(out as *const c_void);
+        }
+
+        let mut calls = 0;
+        let mut action = Callback::new(|value: &[u8]| {
+            calls += 1;
+            match value {
+                b"keep" => ProcAction::Keep,
+                b"drop" => ProcAction::Remove,
+                _ => ProcAction::Replace(b"new".to_vec()),
+            }
+        });
+        let (pdproc, op) = proc_callback(&mut action);
+        unsafe {
+            assert!(pdproc(b"keep".as_ptr(), 4, &mut size, op).is_null());
+            assert_eq!(pdproc(b"drop".as_ptr(), 4, &mut size, op) as isize, -1);
+            let out = pdproc(b"old".as_ptr(), 3, &mut size, op);
+            assert_eq!(slice::from_raw_parts(out, size as usize), b"new");
+            tcfree(out as *const c_void);
+        }
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn test_iter_trampoline() {
+        let mut seen = Vec::new();
+        let mut visit = Callback::new(|k: &[u8], v: &[u8]| {
+            seen.push((k.to_vec(), v.to_vec()));
+            seen.len() < 2
+        });
+        let (iter, op) = iter_callback(&mut visit);
+        unsafe {
+            assert!(iter(b"a".as_ptr(), 1, b"1".as_ptr(), 1, op));
+            assert!(!iter(b"b".as_ptr(), 1, b"2".as_ptr(), 1, op));
+        }
+        visit.resume_panic();
+        assert_eq!(seen, vec![(b"a".to_vec(), b"1".to_vec()), (b"b".to_vec(), b"2".to_vec())]);
+    }
+
+    #[test]
+    fn test_callback_resumes_panic() {
+        // Keep the message of the caught panic out of the test output.
+        let hook = panic::take_hook();
+        panic::set_hook(Box::new(|_| {}));
+        let mut visit = Callback::new(|k: &[u8], _: &[u8]| -> bool { panic!("bad key {:?}", k) });
+        let (iter, op) = iter_callback(&mut visit);
+        assert!(!unsafe { iter(b"a".as_ptr(), 1, b"1".as_ptr(), 1, op) });
+        assert!(!unsafe { iter(b"b".as_ptr(), 1, b"2".as_ptr(), 1, op) });
+        let payload = panic::catch_unwind(AssertUnwindSafe(|| visit.resume_panic())).unwrap_err();
+        panic::set_hook(hook);
+        assert_eq!(payload.downcast_ref::<String>().unwrap(), "bad key [97]");
+        visit.resume_panic();
+    }
+
+    #[test]
+    fn test_error_codes() {
+        assert_eq!(ErrorCode::from_raw(TCENOREC), ErrorCode::NoRec);
+        assert_eq!(ErrorCode::from_raw(TCEMISC).to_raw(), TCEMISC);
+        assert_eq!(ErrorCode::from_raw(42), ErrorCode::Unknown(42));
+        let err = TcError::new(ErrorCode::NoFile, "tchdbopen", Some(Path::new("casket.tch")));
+        assert_eq!(err.code(), ErrorCode::NoFile);
+        assert!(matches!(err, TcError::NoFile(_)));
+        assert_eq!(err.to_string(), "tchdbopen on casket.tch: file not found");
+        let err = TcError::new(ErrorCode::Invalid, "tcbdbtune", None);
+        assert_eq!(err.to_string(), "tcbdbtune: invalid operation");
+        let err = TcError::with_message(ErrorCode::Unknown(42), "tcbdbtune", None, String::from("odd"));
+        assert!(matches!(err, TcError::Unknown(42, _)));
+        assert_eq!(err.code(), ErrorCode::Unknown(42));
+    }
+
+    #[test]
+    fn test_tcbytes() {
+        let mut size: c_int = 0;
+        let bytes = unsafe {
+            let buf = tc_copy(b"hello", &mut size);
+            TcBytes::from_raw(buf, size as usize)
+        };
+        assert_eq!(bytes.len(), 5);
+        assert_eq!(bytes, &b"hello"[..]);
+        assert_eq!(bytes, b"hello".to_vec());
+        assert_eq!(bytes.as_ref(), b"hello");
+        assert_eq!(format!("{:?}", bytes), format!("{:?}", &b"hello"[..]));
+        let v: Vec<u8> = bytes.into();
+        assert_eq!(v, b"hello");
+    }
+
+    #[test]
+    fn test_map_round_trip() {
+        let mut records = ::std::collections::BTreeMap::new();
+        records.insert(b"name".to_vec(), b"alice".to_vec());
+        records.insert(b"age".to_vec(), b"30".to_vec());
+        records.insert(b"empty".to_vec(), Vec::new());
+        unsafe {
+            let map = map_from_btree(&records);
+            assert_eq!(tcmaprnum(map), 3);
+            assert_eq!(map_to_btree(map), records);
+            tcmapdel(map);
+        }
     }
 }